@@ -0,0 +1,359 @@
+use crate::avahi3;
+use crate::command_queue::{Pacing, QueuedCommand};
+use crate::denon_connection::{record, LineHandler, Received, QUERY_TIMEOUT};
+use crate::error::Error;
+use crate::logger::Logger;
+use crate::model::Model;
+use crate::state::{SetState, State, StateValue};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, ErrorKind};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+
+// changes a slow subscriber has not received yet, older ones are dropped
+const EVENT_CAPACITY: usize = 64;
+const CHUNK_SIZE: usize = 4096;
+
+// data shared between AsyncDenonConnection and its reader task
+struct Shared {
+    model: &'static Model,
+    state: Mutex<HashMap<State, Received>>,
+    // states whose query was sent, but not answered yet
+    awaiting: Mutex<HashSet<State>>,
+    parse_errors: AtomicUsize,
+    // dropped by the reader task when the connection is lost
+    changes: Mutex<Option<broadcast::Sender<SetState>>>,
+}
+
+async fn reader_task(
+    mut stream: OwnedReadHalf,
+    shared: Arc<Shared>,
+    logger: Arc<dyn Logger>,
+) -> Result<(), io::Error> {
+    let mut handler = LineHandler::new(shared.model);
+    let mut chunk = [0; CHUNK_SIZE];
+    let result = loop {
+        let read_bytes = match stream.read(&mut chunk).await {
+            Ok(0) => break Ok(()),
+            Ok(read_bytes) => read_bytes,
+            Err(e) => break Err(e),
+        };
+        handler.extend(&chunk[..read_bytes]);
+        let (_, parsed) = handler.take_lines(&shared.parse_errors, logger.as_ref());
+        for sstate in parsed {
+            {
+                let mut state = shared.state.lock().unwrap();
+                record(sstate, &mut state, &mut shared.awaiting.lock().unwrap());
+            }
+            if let Some(changes) = shared.changes.lock().unwrap().as_ref() {
+                // nobody listening is fine
+                let _ = changes.send(sstate);
+            }
+        }
+    };
+    // waiting queries and event streams end
+    shared.changes.lock().unwrap().take();
+    result
+}
+
+struct Writer {
+    to_receiver: OwnedWriteHalf,
+    // the next command is not sent before, see Pacing
+    ready_at: Instant,
+}
+
+/// Connection to the receiver for tokio applications. It shares the parser
+/// and the states with `DenonConnection`, but reads in a task instead of a
+/// thread of its own.
+pub struct AsyncDenonConnection {
+    shared: Arc<Shared>,
+    writer: tokio::sync::Mutex<Writer>,
+    pacing: Pacing,
+    reader: JoinHandle<Result<(), io::Error>>,
+}
+
+impl AsyncDenonConnection {
+    pub async fn connect(
+        denon_name: &str,
+        denon_port: u16,
+        logger: Arc<dyn Logger>,
+        model: &'static Model,
+    ) -> Result<AsyncDenonConnection, io::Error> {
+        let stream = TcpStream::connect((denon_name, denon_port)).await?;
+        Ok(AsyncDenonConnection::new(stream, logger, model))
+    }
+
+    /// Must be called within a tokio runtime, which runs the reader task.
+    pub fn new(
+        stream: TcpStream,
+        logger: Arc<dyn Logger>,
+        model: &'static Model,
+    ) -> AsyncDenonConnection {
+        AsyncDenonConnection::with_pacing(stream, logger, model, Pacing::default())
+    }
+
+    pub fn with_pacing(
+        stream: TcpStream,
+        logger: Arc<dyn Logger>,
+        model: &'static Model,
+        pacing: Pacing,
+    ) -> AsyncDenonConnection {
+        let (from_receiver, to_receiver) = stream.into_split();
+        let shared = Arc::new(Shared {
+            model,
+            state: Mutex::default(),
+            awaiting: Mutex::default(),
+            parse_errors: AtomicUsize::default(),
+            changes: Mutex::new(Some(broadcast::channel(EVENT_CAPACITY).0)),
+        });
+        let reader = tokio::spawn(reader_task(from_receiver, shared.clone(), logger));
+        AsyncDenonConnection {
+            shared,
+            writer: tokio::sync::Mutex::new(Writer {
+                to_receiver,
+                ready_at: Instant::now(),
+            }),
+            pacing,
+            reader,
+        }
+    }
+
+    pub async fn get(&self, op: State) -> Result<StateValue, Error> {
+        self.get_timeout(op, QUERY_TIMEOUT).await
+    }
+
+    /// Returns the last received value of `op`. If there is none, the
+    /// receiver is queried and its answer is awaited at most `timeout`.
+    pub async fn get_timeout(&self, op: State, timeout: Duration) -> Result<StateValue, Error> {
+        if let Some(received) = self.received(op) {
+            return Ok(received.value);
+        }
+        // subscribed before querying, so the answer cannot be missed
+        let mut changes = self.subscribe()?;
+        self.shared.awaiting.lock().unwrap().insert(op);
+        self.write(QueuedCommand::query(op)).await?;
+        let answer = async {
+            loop {
+                match changes.recv().await {
+                    Ok(sstate) if sstate.convert().0 == op => return Ok(sstate.convert().1),
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(_)) => {
+                        if let Some(received) = self.received(op) {
+                            return Ok(received.value);
+                        }
+                    }
+                    Err(RecvError::Closed) => {
+                        return Err(io::Error::from(ErrorKind::NotConnected).into())
+                    }
+                }
+            }
+        };
+        tokio::time::timeout(timeout, answer)
+            .await
+            .unwrap_or(Err(Error::Timeout(op)))
+    }
+
+    pub async fn set(&self, sstate: SetState) -> Result<(), io::Error> {
+        self.write(QueuedCommand::set(sstate, self.shared.model))
+            .await
+    }
+
+    /// Returns a stream of all states received from now on, which ends when
+    /// the connection is lost.
+    pub fn events(&self) -> impl Stream<Item = SetState> + Send + Unpin {
+        let receiver = match self.subscribe() {
+            Ok(receiver) => receiver,
+            // a closed channel makes an empty stream
+            Err(_) => broadcast::channel(1).1,
+        };
+        // lagging subscribers miss some states, but keep receiving
+        BroadcastStream::new(receiver).filter_map(Result::ok)
+    }
+
+    /// Returns the last received value of `op` with its age and origin.
+    pub fn received(&self, op: State) -> Option<Received> {
+        self.shared.state.lock().unwrap().get(&op).copied()
+    }
+
+    pub fn model(&self) -> &'static Model {
+        self.shared.model
+    }
+
+    /// Number of lines received from the receiver which could not be parsed.
+    pub fn parse_errors(&self) -> usize {
+        self.shared.parse_errors.load(Ordering::Relaxed)
+    }
+
+    fn subscribe(&self) -> Result<broadcast::Receiver<SetState>, io::Error> {
+        match self.shared.changes.lock().unwrap().as_ref() {
+            Some(changes) => Ok(changes.subscribe()),
+            None => Err(io::Error::from(ErrorKind::NotConnected)),
+        }
+    }
+
+    /// Writes `command` with the same pauses as the writer thread of
+    /// `DenonConnection`. The lock is kept while pausing, so commands are
+    /// sent in the order of the calls.
+    async fn write(&self, command: QueuedCommand) -> Result<(), io::Error> {
+        let mut writer = self.writer.lock().await;
+        tokio::time::sleep_until(writer.ready_at).await;
+        let result = writer
+            .to_receiver
+            .write_all(command.line().as_bytes())
+            .await;
+        writer.ready_at = Instant::now() + self.pacing.pause(&command);
+        result
+    }
+}
+
+impl Drop for AsyncDenonConnection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Finds a receiver with avahi without blocking the runtime.
+pub async fn discover(logger: Arc<dyn Logger>) -> Result<String, Error> {
+    let found = tokio::task::spawn_blocking(move || avahi3::get_receiver(logger.as_ref()))
+        .await
+        .map_err(io::Error::other)?;
+    Ok(found?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::AsyncDenonConnection;
+    use crate::command_queue::Pacing;
+    use crate::denon_connection::Origin;
+    use crate::error::Error;
+    use crate::logger::StdoutLogger;
+    use crate::model::default_model;
+    use crate::state::{PowerState, SetState, SourceInputState, State, StateValue};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_stream::StreamExt;
+
+    async fn create_connected_connection() -> Result<(TcpStream, AsyncDenonConnection), Error> {
+        let listener = TcpListener::bind("localhost:0").await?;
+        let address = listener.local_addr()?;
+        let dc = AsyncDenonConnection::connect(
+            &address.ip().to_string(),
+            address.port(),
+            Arc::new(StdoutLogger::default()),
+            default_model(),
+        )
+        .await?;
+        Ok((listener.accept().await?.0, dc))
+    }
+
+    async fn read_line(stream: &mut TcpStream) -> Result<String, Error> {
+        let mut line = Vec::new();
+        loop {
+            match stream.read_u8().await? {
+                b'\r' => return Ok(String::from_utf8_lossy(&line).into_owned()),
+                byte => line.push(byte),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn get_queries_receiver() -> Result<(), Error> {
+        let (mut to_denon_client, dc) = create_connected_connection().await?;
+        let responder = tokio::spawn(async move {
+            let query = read_line(&mut to_denon_client).await?;
+            to_denon_client.write_all(b"PWON\r").await?;
+            Ok::<_, Error>((query, to_denon_client))
+        });
+        assert_eq!(
+            StateValue::Power(PowerState::On),
+            dc.get(State::Power).await?
+        );
+        let (query, _to_denon_client) = responder.await.unwrap()?;
+        assert_eq!("PW?", query);
+        assert_eq!(
+            Some(Origin::Reply),
+            dc.received(State::Power).map(|r| r.origin)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reader_counts_malformed_lines() -> Result<(), Error> {
+        let (mut to_denon_client, dc) = create_connected_connection().await?;
+        let mut events = dc.events();
+        to_denon_client.write_all(b"MV\rMVMAXabc\rSICD\r").await?;
+        assert_eq!(
+            Some(SetState::SourceInput(SourceInputState::Cd)),
+            events.next().await
+        );
+        assert_eq!(2, dc.parse_errors());
+        assert_eq!(
+            Some(Origin::Event),
+            dc.received(State::SourceInput).map(|r| r.origin)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_times_out_without_answer() -> Result<(), Error> {
+        let (_to_denon_client, dc) = create_connected_connection().await?;
+        let rc = dc
+            .get_timeout(State::MainVolume, Duration::from_millis(50))
+            .await;
+        assert!(matches!(rc, Err(Error::Timeout(State::MainVolume))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_writes_command_and_events_are_streamed() -> Result<(), Error> {
+        let (mut to_denon_client, dc) = create_connected_connection().await?;
+        let mut events = dc.events();
+        dc.set(SetState::MainVolume(230)).await?;
+        assert_eq!("MV23", read_line(&mut to_denon_client).await?);
+
+        to_denon_client.write_all(b"MV230\rSICD\r").await?;
+        assert_eq!(Some(SetState::MainVolume(230)), events.next().await);
+        assert_eq!(StateValue::Integer(230), dc.get(State::MainVolume).await?);
+        drop(to_denon_client);
+        assert!(events.next().await.is_some());
+        assert_eq!(None, events.next().await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_keeps_pacing() -> Result<(), Error> {
+        let listener = TcpListener::bind("localhost:0").await?;
+        let pacing = Pacing {
+            interval: Duration::from_millis(50),
+            power_on_delay: Duration::from_millis(200),
+        };
+        let dc = AsyncDenonConnection::with_pacing(
+            TcpStream::connect(listener.local_addr()?).await?,
+            Arc::new(StdoutLogger::default()),
+            default_model(),
+            pacing,
+        );
+        let mut to_denon_client = listener.accept().await?.0;
+        let start = Instant::now();
+        dc.set(SetState::Power(PowerState::On)).await?;
+        dc.set(SetState::MainVolume(230)).await?;
+        assert!(start.elapsed() >= pacing.power_on_delay);
+        dc.set(SetState::SourceInput(SourceInputState::Cd)).await?;
+        assert!(start.elapsed() >= pacing.power_on_delay + pacing.interval);
+        for expected in ["PWON", "MV23", "SICD"] {
+            assert_eq!(expected, read_line(&mut to_denon_client).await?);
+        }
+        Ok(())
+    }
+}
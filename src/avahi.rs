@@ -1,19 +1,46 @@
 use crate::{avahi_error::Error, logger::Logger};
 use std::process::Command;
 
-pub fn get_receiver(logger: &dyn Logger) -> Result<String, Error> {
+fn browse_raop() -> Result<String, Error> {
     let output = Command::new("/usr/bin/avahi-browse")
         .arg("-p")
         .arg("-t")
         .arg("-r")
         .arg("_raop._tcp")
         .output()?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
 
-    let output_stdout = String::from_utf8_lossy(&output.stdout);
-    let lines = output_stdout.lines();
-    let denon_names: Vec<&str> = lines
+fn denon_lines(output: &str) -> impl Iterator<Item = &str> {
+    output
+        .lines()
         .filter(|&line| line.starts_with('='))
         .filter(|&line| line.contains("DENON"))
+}
+
+// the txt record is the 10th field and contains entries like "am=AVR-X2700H"
+fn parse_models(output: &str) -> Vec<String> {
+    denon_lines(output)
+        .filter_map(|line| line.split(';').nth(9))
+        .filter_map(|txt| {
+            txt.split('"')
+                .find_map(|entry| entry.strip_prefix("am="))
+                .map(String::from)
+        })
+        .collect()
+}
+
+pub fn get_model(_logger: &dyn Logger) -> Result<String, Error> {
+    let output = browse_raop()?;
+    parse_models(&output)
+        .into_iter()
+        .next()
+        .ok_or(Error::NoHostsFound)
+}
+
+pub fn get_receiver(logger: &dyn Logger) -> Result<String, Error> {
+    let output_stdout = browse_raop()?;
+    let denon_names: Vec<&str> = denon_lines(&output_stdout)
         .map(|line| line.split(';'))
         .map(|mut iter| iter.nth(6).unwrap())
         .collect();
@@ -35,7 +62,7 @@ pub fn get_receiver(logger: &dyn Logger) -> Result<String, Error> {
 
 #[cfg(test)]
 mod test {
-    use super::get_receiver;
+    use super::{get_receiver, parse_models};
     use crate::{avahi_error::Error, logger::MockLogger};
     use std::net::TcpStream;
 
@@ -50,4 +77,13 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn parse_models_reads_txt_record() {
+        let output = "+;eth0;IPv4;0005CD221B08@DENON AVR-X2700H;AirTunes Remote Audio;local\n\
+            =;eth0;IPv4;0005CD221B08@DENON AVR-X2700H;AirTunes Remote Audio;local;denon.local;192.168.1.2;1024;\"tp=UDP\" \"am=AVR-X2700H\" \"md=0,1,2\"\n\
+            =;eth0;IPv4;AABBCC@Kitchen;AirTunes Remote Audio;local;other.local;192.168.1.3;1024;\"am=AppleTV3,2\"\n";
+        assert_eq!(vec!["AVR-X2700H"], parse_models(output));
+        assert_eq!(Vec::<String>::new(), parse_models(""));
+    }
 }
@@ -75,9 +75,25 @@ pub fn get_receiver(logger: &dyn Logger) -> Result<String, Error> {
     Err(Error::NoHostsFound)
 }
 
+// the txt record entry am contains the model, e.g. AVR-X2700H
+fn get_model_of(sd: &ServiceDiscovery) -> Option<String> {
+    sd.txt()
+        .as_ref()?
+        .iter()
+        .find(|(key, _)| key == "am")
+        .map(|(_, value)| value)
+}
+
+pub fn get_model(logger: &dyn Logger) -> Result<String, Error> {
+    let sd = get_hostname(get_roap_service_type(), logger)?;
+    get_model_of(&sd).ok_or(Error::NoHostsFound)
+}
+
 #[cfg(test)]
 mod test {
-    use super::{get_receiver, get_roap_service_type, on_service_discovered, Context};
+    use super::{
+        get_model_of, get_receiver, get_roap_service_type, on_service_discovered, Context,
+    };
     use crate::{avahi3::get_hostname, avahi_error::Error, logger::MockLogger};
     use std::{
         net::TcpStream,
@@ -141,6 +157,11 @@ mod test {
         ));
     }
 
+    #[test]
+    fn get_model_of_without_txt_record() {
+        assert_eq!(None, get_model_of(&create_service_discovery()));
+    }
+
     #[test]
     fn on_service_discovered_works() {
         let sd = create_service_discovery();
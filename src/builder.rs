@@ -0,0 +1,179 @@
+use crate::command_queue::Pacing;
+use crate::denon_connection::{Backoff, DenonConnection};
+use crate::error::Error;
+use crate::http_connection::HttpConnection;
+use crate::logger::{Logger, StdoutLogger};
+use crate::model::Model;
+use crate::receiver::Receiver;
+use crate::stream::{
+    create_tcp_stream_with, tcp_stream_factory_with, ConnectionStream, TcpOptions,
+};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Configures the connection to the receiver before connecting.
+#[derive(Clone)]
+pub struct ConnectionBuilder {
+    model: &'static Model,
+    tcp: TcpOptions,
+    // None keeps the default of the transport
+    query_timeout: Option<Duration>,
+    pacing: Pacing,
+    logger: Arc<dyn Logger>,
+    // None gives up once the connection is lost
+    reconnect: Option<Backoff>,
+}
+
+impl ConnectionBuilder {
+    pub fn new(model: &'static Model) -> ConnectionBuilder {
+        ConnectionBuilder {
+            model,
+            tcp: TcpOptions::default(),
+            query_timeout: None,
+            pacing: Pacing::default(),
+            logger: Arc::new(StdoutLogger::default()),
+            reconnect: None,
+        }
+    }
+
+    /// `None` waits as long as the operating system does.
+    pub fn connect_timeout(mut self, timeout: Option<Duration>) -> ConnectionBuilder {
+        self.tcp.connect_timeout = timeout;
+        self
+    }
+
+    /// How long a single read from the telnet socket blocks, unlike
+    /// `query_timeout()`, which limits the wait for an answer.
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> ConnectionBuilder {
+        self.tcp.read_timeout = timeout;
+        self
+    }
+
+    /// Probes an idle connection after `idle`, so a receiver which was
+    /// switched off is noticed.
+    pub fn keepalive(mut self, idle: Option<Duration>) -> ConnectionBuilder {
+        self.tcp.keepalive = idle;
+        self
+    }
+
+    pub(crate) fn tcp_options(mut self, tcp: TcpOptions) -> ConnectionBuilder {
+        self.tcp = tcp;
+        self
+    }
+
+    /// How long `get()` waits for the answer of the receiver.
+    pub fn query_timeout(mut self, timeout: Duration) -> ConnectionBuilder {
+        self.query_timeout = Some(timeout);
+        self
+    }
+
+    pub fn pacing(mut self, pacing: Pacing) -> ConnectionBuilder {
+        self.pacing = pacing;
+        self
+    }
+
+    pub fn logger(mut self, logger: Arc<dyn Logger>) -> ConnectionBuilder {
+        self.logger = logger;
+        self
+    }
+
+    /// Connects again with the delays of `backoff` whenever the connection is lost.
+    pub fn reconnect(mut self, backoff: Backoff) -> ConnectionBuilder {
+        self.reconnect = Some(backoff);
+        self
+    }
+
+    /// Connects with telnet, which only one client may use at a time.
+    pub fn connect(self, denon_name: &str, denon_port: u16) -> Result<Receiver, Error> {
+        Ok(Receiver::new(Box::new(self.denon(denon_name, denon_port)?)))
+    }
+
+    /// Uses the web interface, which may be shared with other clients and
+    /// connects for every request.
+    pub fn http(self, host: &str, port: u16) -> Receiver {
+        let mut hc = HttpConnection::new(host, port, self.logger, self.model);
+        hc.set_tcp_options(self.tcp);
+        if let Some(timeout) = self.query_timeout {
+            hc.set_query_timeout(timeout);
+        }
+        Receiver::new(Box::new(hc))
+    }
+
+    /// Talks over an already opened `stream`, e.g. a serial port, which
+    /// cannot be opened again after it was lost.
+    pub(crate) fn open(self, stream: Box<dyn ConnectionStream>) -> Result<Receiver, io::Error> {
+        let mut dc = DenonConnection::with_pacing(stream, self.logger, self.model, self.pacing)?;
+        if let Some(timeout) = self.query_timeout {
+            dc.set_query_timeout(timeout);
+        }
+        Ok(Receiver::new(Box::new(dc)))
+    }
+
+    fn denon(self, denon_name: &str, denon_port: u16) -> Result<DenonConnection, io::Error> {
+        let mut dc = match self.reconnect {
+            Some(backoff) => DenonConnection::reconnecting(
+                tcp_stream_factory_with(denon_name, denon_port, self.tcp),
+                backoff,
+                self.logger,
+                self.model,
+                self.pacing,
+            )?,
+            None => DenonConnection::with_pacing(
+                create_tcp_stream_with(denon_name, denon_port, &self.tcp)?,
+                self.logger,
+                self.model,
+                self.pacing,
+            )?,
+        };
+        if let Some(timeout) = self.query_timeout {
+            dc.set_query_timeout(timeout);
+        }
+        Ok(dc)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConnectionBuilder;
+    use crate::denon_connection::Backoff;
+    use crate::error::Error;
+    use crate::logger::MockLogger;
+    use crate::model::default_model;
+    use crate::state::State;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn connection_uses_query_timeout() -> Result<(), Error> {
+        let listener = TcpListener::bind("localhost:0")?;
+        let mut logger = MockLogger::new();
+        logger.expect_log().return_const(());
+        let mut dc = ConnectionBuilder::new(default_model())
+            .connect_timeout(Some(Duration::from_secs(1)))
+            .keepalive(Some(Duration::from_secs(30)))
+            .query_timeout(Duration::from_millis(50))
+            .logger(Arc::new(logger))
+            .reconnect(Backoff::default())
+            .denon("localhost", listener.local_addr()?.port())?;
+        let _to_denon_client = listener.accept()?;
+
+        assert_eq!(Duration::from_millis(50), dc.query_timeout());
+        assert!(matches!(
+            dc.get(State::Power),
+            Err(Error::Timeout(State::Power))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn connect_fails_without_receiver() {
+        let port = TcpListener::bind("localhost:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port();
+        let rc = ConnectionBuilder::new(default_model()).connect("localhost", port);
+        assert!(rc.is_err());
+    }
+}
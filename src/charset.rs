@@ -0,0 +1,142 @@
+use std::fmt::{Display, Formatter};
+
+/// Decodes text sent by the receiver. Newer models send UTF-8, older ones
+/// and renamed sources use Latin-1, where every byte is its own code point.
+pub fn decode_text(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => bytes.iter().map(|&b| char::from(b)).collect(),
+    }
+}
+
+/// Icons shown in front of the entries of the onscreen display, which the
+/// receiver sends as a bit mask in the byte after the line number.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DisplayFlags {
+    pub cursor: bool,
+    pub playable: bool,
+    pub directory: bool,
+}
+
+impl DisplayFlags {
+    const CURSOR: u8 = 0x01;
+    const PLAYABLE: u8 = 0x02;
+    const DIRECTORY: u8 = 0x08;
+
+    fn from_byte(byte: u8) -> DisplayFlags {
+        DisplayFlags {
+            cursor: byte & DisplayFlags::CURSOR != 0,
+            playable: byte & DisplayFlags::PLAYABLE != 0,
+            directory: byte & DisplayFlags::DIRECTORY != 0,
+        }
+    }
+
+    fn names(&self) -> Vec<&'static str> {
+        [
+            (self.cursor, "cursor"),
+            (self.playable, "playable"),
+            (self.directory, "directory"),
+        ]
+        .into_iter()
+        .filter_map(|(set, name)| set.then_some(name))
+        .collect()
+    }
+}
+
+/// A line of the onscreen display like `NSE1<flags>Some Song`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisplayLine {
+    pub prefix: &'static str,
+    pub number: u8,
+    pub flags: DisplayFlags,
+    pub text: String,
+}
+
+impl DisplayLine {
+    pub fn parse(line: &str) -> Option<DisplayLine> {
+        let prefix = ["NSA", "NSE"].into_iter().find(|p| line.starts_with(p))?;
+        let mut chars = line[prefix.len()..].chars();
+        let number = chars.next()?.to_digit(10)? as u8;
+        let mut text = chars.as_str();
+        let mut flags = DisplayFlags::default();
+        // the title in line 0 has no icons
+        if number > 0 {
+            if let Some(first) = text.chars().next().filter(|c| c.is_ascii_control()) {
+                flags = DisplayFlags::from_byte(first as u8);
+                text = &text[1..];
+            }
+        }
+        Some(DisplayLine {
+            prefix,
+            number,
+            flags,
+            text: text.trim().to_string(),
+        })
+    }
+}
+
+impl Display for DisplayLine {
+    fn fmt(&self, format: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(format, "{}{}", self.prefix, self.number)?;
+        let names = self.flags.names();
+        if !names.is_empty() {
+            write!(format, " [{}]", names.join(", "))?;
+        }
+        write!(format, " {}", self.text)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_text, DisplayFlags, DisplayLine};
+
+    #[test]
+    fn decodes_utf8_and_latin1() {
+        assert_eq!("NSE1Motörhead", decode_text("NSE1Motörhead".as_bytes()));
+        assert_eq!("NSE1Motörhead", decode_text(b"NSE1Mot\xf6rhead"));
+        assert_eq!("SITV Ça été", decode_text(b"SITV \xc7a \xe9t\xe9"));
+        assert_eq!("PWON", decode_text(b"PWON"));
+    }
+
+    #[test]
+    fn parses_display_lines() {
+        assert_eq!(
+            Some(DisplayLine {
+                prefix: "NSE",
+                number: 1,
+                flags: DisplayFlags {
+                    cursor: true,
+                    playable: true,
+                    directory: false
+                },
+                text: String::from("Björk"),
+            }),
+            DisplayLine::parse("NSE1\u{3}Björk")
+        );
+        assert_eq!(
+            Some(DisplayLine {
+                prefix: "NSA",
+                number: 0,
+                flags: DisplayFlags::default(),
+                text: String::from("Now Playing"),
+            }),
+            DisplayLine::parse("NSA0Now Playing")
+        );
+        assert_eq!(None, DisplayLine::parse("NSEX"));
+        assert_eq!(None, DisplayLine::parse("MV50"));
+    }
+
+    #[test]
+    fn display_shows_flags() {
+        assert_eq!(
+            "NSE2 [cursor, directory] Favorites",
+            DisplayLine::parse("NSE2\u{9}Favorites")
+                .unwrap()
+                .to_string()
+        );
+        assert_eq!(
+            "NSE3 Song",
+            DisplayLine::parse("NSE3Song").unwrap().to_string()
+        );
+    }
+}
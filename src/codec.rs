@@ -0,0 +1,199 @@
+use crate::stream::ReadStream;
+use std::io;
+
+// lines longer than this are garbage, e.g. after connecting in the middle of
+// a binary transfer, and are dropped until the next line end
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+const CHUNK_SIZE: usize = 4096;
+
+/// Splits the bytes received from the receiver into lines ending with `\r`.
+pub struct LineCodec {
+    buffer: Vec<u8>,
+    // start of the first line not yet returned
+    start: usize,
+    // start of the data not yet searched for a line end
+    searched: usize,
+    max_line_length: usize,
+    discarding: bool,
+    discarded_lines: usize,
+}
+
+impl Default for LineCodec {
+    fn default() -> Self {
+        LineCodec::new(MAX_LINE_LENGTH)
+    }
+}
+
+impl LineCodec {
+    pub fn new(max_line_length: usize) -> LineCodec {
+        LineCodec {
+            buffer: Vec::new(),
+            start: 0,
+            searched: 0,
+            max_line_length,
+            discarding: false,
+            discarded_lines: 0,
+        }
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Reads whatever is available from `stream` into the buffer. Returns
+    /// the number of read bytes, which is 0 after a shutdown.
+    pub fn fill(&mut self, stream: &dyn ReadStream) -> Result<usize, io::Error> {
+        let mut chunk = [0; CHUNK_SIZE];
+        let read_bytes = stream.peekly(&mut chunk)?;
+        if read_bytes > 0 {
+            stream.read_exactly(&mut chunk[..read_bytes])?;
+            self.extend(&chunk[..read_bytes]);
+        }
+        Ok(read_bytes)
+    }
+
+    /// Returns the next complete line without its line end.
+    pub fn next_line(&mut self) -> Option<Vec<u8>> {
+        loop {
+            match self.buffer[self.searched..]
+                .iter()
+                .position(|&c| c == b'\r')
+            {
+                Some(pos) => {
+                    let end = self.searched + pos;
+                    let line = self.buffer[self.start..end].to_vec();
+                    self.start = end + 1;
+                    self.searched = self.start;
+                    if self.discarding {
+                        // the rest of the overlong line
+                        self.discarding = false;
+                        continue;
+                    }
+                    return Some(line);
+                }
+                None => {
+                    // returned lines are removed at once instead of one by one
+                    self.buffer.drain(..self.start);
+                    self.start = 0;
+                    self.searched = self.buffer.len();
+                    if self.buffer.len() > self.max_line_length {
+                        if !self.discarding {
+                            self.discarded_lines += 1;
+                        }
+                        self.discarding = true;
+                        self.buffer.clear();
+                        self.searched = 0;
+                    }
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Number of overlong lines, which were dropped to resynchronise.
+    pub fn discarded_lines(&self) -> usize {
+        self.discarded_lines
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LineCodec;
+    use crate::stream::MockReadStream;
+    use std::cmp::min;
+
+    fn lines(codec: &mut LineCodec) -> Vec<String> {
+        std::iter::from_fn(|| codec.next_line())
+            .map(|line| String::from_utf8_lossy(&line).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn splits_lines() {
+        let mut codec = LineCodec::default();
+        codec.extend(b"PWON\rMV50\r\rSI");
+        assert_eq!(vec!["PWON", "MV50", ""], lines(&mut codec));
+        codec.extend(b"CD\r");
+        assert_eq!(vec!["SICD"], lines(&mut codec));
+        assert!(codec.next_line().is_none());
+    }
+
+    #[test]
+    fn handles_partial_reads_byte_by_byte() {
+        let mut codec = LineCodec::default();
+        let mut result = Vec::new();
+        for byte in b"NSE1Some Song\rMV50\r" {
+            codec.extend(&[*byte]);
+            result.append(&mut lines(&mut codec));
+        }
+        assert_eq!(vec!["NSE1Some Song", "MV50"], result);
+    }
+
+    #[test]
+    fn handles_long_lines() {
+        let mut codec = LineCodec::default();
+        let long_line = format!("NSE{}", "x".repeat(5000));
+        codec.extend(format!("{}\rPWON\r", long_line).as_bytes());
+        assert_eq!(vec![long_line.as_str(), "PWON"], lines(&mut codec));
+    }
+
+    #[test]
+    fn resynchronises_after_overlong_line() {
+        let mut codec = LineCodec::new(8);
+        codec.extend(b"0123456789");
+        assert!(codec.next_line().is_none());
+        codec.extend(b"abc\rPWON\r");
+        assert_eq!(vec!["PWON"], lines(&mut codec));
+        assert_eq!(1, codec.discarded_lines());
+
+        codec.extend(b"MV50\r");
+        assert_eq!(vec!["MV50"], lines(&mut codec));
+        assert_eq!(1, codec.discarded_lines());
+    }
+
+    #[test]
+    fn fill_reads_from_stream() {
+        let mut stream = MockReadStream::new();
+        stream.expect_peekly().once().returning(|buf| {
+            buf[..5].copy_from_slice(b"PWON\r");
+            Ok(5)
+        });
+        stream
+            .expect_read_exactly()
+            .once()
+            .withf(|buf| buf.len() == 5)
+            .returning(|_| Ok(()));
+        let mut codec = LineCodec::default();
+        assert_eq!(5, codec.fill(&stream).unwrap());
+        assert_eq!(vec!["PWON"], lines(&mut codec));
+
+        let mut stream = MockReadStream::new();
+        stream.expect_peekly().once().returning(|_| Ok(0));
+        assert_eq!(0, codec.fill(&stream).unwrap());
+    }
+
+    #[test]
+    fn decodes_bursts_in_bounded_buffer() {
+        let burst: Vec<u8> = (0..100_000)
+            .flat_map(|i| {
+                format!("MV{}\rNSE{}{}\r", i % 99, i % 9, "x".repeat(i % 300)).into_bytes()
+            })
+            .collect();
+        let mut codec = LineCodec::default();
+        let mut count = 0;
+        // uneven chunks, which split lines at arbitrary positions
+        let mut offset = 0;
+        let mut chunk_size = 1;
+        while offset < burst.len() {
+            let end = min(burst.len(), offset + chunk_size);
+            codec.extend(&burst[offset..end]);
+            count += std::iter::from_fn(|| codec.next_line()).count();
+            // returned lines are dropped and never searched again
+            assert!(codec.buffer.len() < 5300);
+            assert_eq!(codec.buffer.len(), codec.searched);
+            offset = end;
+            chunk_size = chunk_size * 7 % 5000 + 1;
+        }
+        assert_eq!(200_000, count);
+    }
+}
@@ -0,0 +1,403 @@
+use crate::error::Error;
+use crate::model::Model;
+use crate::state::{get_state, PowerState, SourceInputState, StateValue};
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    Main,
+}
+
+impl Display for Zone {
+    fn fmt(&self, format: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(format, "{}", format!("{:?}", self).to_ascii_uppercase())
+    }
+}
+
+// the highest main volume in whole steps, which may be set
+pub const MAIN_VOLUME_CAP: u32 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueKind {
+    Power,
+    SourceInput,
+    // values are tenths of a step, e.g. 455 for 45.5, the receiver sends two
+    // digits for whole steps and three digits for half steps
+    // given values are whole steps, those above max are capped to not
+    // accidentally kill the ears
+    Volume { min: u32, max: u32 },
+}
+
+impl ValueKind {
+    pub fn allowed_values(&self) -> String {
+        let join = |values: Vec<String>| values.join(", ");
+        match *self {
+            ValueKind::Power => join(PowerState::states().iter().map(|s| s.to_string()).collect()),
+            ValueKind::SourceInput => join(
+                SourceInputState::states()
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect(),
+            ),
+            ValueKind::Volume { min, max } => format!("{}..{}", min, max),
+        }
+    }
+
+    // values as sent by the receiver, which are accepted as they are
+    fn parse_received(&self, value: &str, model: &Model) -> Result<StateValue, &'static str> {
+        match *self {
+            ValueKind::Power => Ok(StateValue::Power(
+                get_state(PowerState::states(), value).unwrap_or(PowerState::Standby),
+            )),
+            ValueKind::SourceInput => Ok(StateValue::SourceInput(
+                model
+                    .decode_source(value)
+                    .unwrap_or(SourceInputState::Unknown),
+            )),
+            ValueKind::Volume { .. } => {
+                let volume = value
+                    .parse::<u32>()
+                    .map_err(|_| "a volume of two or three digits")?;
+                // the third digit is the half step, e.g. 095 is 9.5
+                match value.len() {
+                    3 => Ok(StateValue::Integer(volume)),
+                    _ => Ok(StateValue::Integer(volume * 10)),
+                }
+            }
+        }
+    }
+
+    // values given by the user, which may differ in case or use aliases
+    fn parse_input(&self, value: &str, model: &Model) -> Result<StateValue, Error> {
+        match *self {
+            ValueKind::Power => Ok(StateValue::Power(value.parse()?)),
+            ValueKind::SourceInput => {
+                let source = match model.decode_source(value) {
+                    Some(source) => source,
+                    None => value.parse()?,
+                };
+                if !model.supports_source(source) {
+                    return Err(Error::Input(format!(
+                        "source input {} is not supported by {}",
+                        value, model.name
+                    )));
+                }
+                Ok(StateValue::SourceInput(source))
+            }
+            ValueKind::Volume { min, max } => {
+                let volume = value.parse::<u32>()?;
+                if volume < min {
+                    return Err(Error::Input(format!(
+                        "given value {} is not in range {}..{}",
+                        volume, min, max
+                    )));
+                }
+                Ok(StateValue::Integer(volume.min(max) * 10))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CliOption {
+    pub short: &'static str,
+    pub long: &'static str,
+    pub hint: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Command {
+    pub state: State,
+    pub name: &'static str,
+    pub prefix: &'static str,
+    pub kind: ValueKind,
+    pub zones: &'static [Zone],
+    pub queryable: bool,
+    pub description: &'static str,
+    pub option: Option<CliOption>,
+    // element holding the state on the status page of the web interface
+    pub xml_tag: Option<&'static str>,
+}
+
+impl Command {
+    pub fn help(&self) -> String {
+        format!("{}: {}", self.description, self.kind.allowed_values())
+    }
+
+    /// Parses the value of a line received from the receiver.
+    pub fn parse_received(&self, value: &str, model: &Model) -> Result<SetState, &'static str> {
+        let value = self.kind.parse_received(value, model)?;
+        SetState::from_value(self.state, value).ok_or("a value matching the command")
+    }
+
+    /// Parses and validates a value given by the user.
+    pub fn parse_input(&self, value: &str, model: &Model) -> Result<SetState, Error> {
+        if !model.supports(self.state) {
+            return Err(Error::Input(format!(
+                "{} is not supported by {}",
+                self.name, model.name
+            )));
+        }
+        let state_value = self.kind.parse_input(value, model)?;
+        SetState::from_value(self.state, state_value)
+            .ok_or_else(|| Error::Input(format!("{} cannot be set to {}", self.name, value)))
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, format: &mut Formatter) -> Result<(), std::fmt::Error> {
+        let zones: Vec<String> = self.zones.iter().map(|z| z.to_string()).collect();
+        let option = self
+            .option
+            .map(|o| format!("--{}", o.long))
+            .unwrap_or_default();
+        write!(
+            format,
+            "{:<6}{:<13}{:<7}{:<6}{:<10}{}",
+            self.prefix,
+            self.name,
+            zones.join(","),
+            if self.queryable { "?" } else { "" },
+            option,
+            self.kind.allowed_values()
+        )
+    }
+}
+
+// Generates `State`, `SetState`, their conversions and the command table
+// from the registry below, so a command is added in one place only.
+macro_rules! registry {
+    ($($state:ident($value:ty) as $variant:ident { $($field:ident: $init:expr),* $(,)? }),* $(,)?) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #[non_exhaustive]
+        pub enum State {
+            $($state),*
+        }
+
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[non_exhaustive]
+        pub enum SetState {
+            $($state($value)),*
+        }
+
+        impl SetState {
+            pub fn convert(&self) -> (State, StateValue) {
+                match *self {
+                    $(SetState::$state(v) => (State::$state, StateValue::$variant(v)),)*
+                }
+            }
+
+            pub fn from_value(state: State, value: StateValue) -> Option<SetState> {
+                match (state, value) {
+                    $((State::$state, StateValue::$variant(v)) => Some(SetState::$state(v)),)*
+                    _ => None,
+                }
+            }
+        }
+
+        static COMMANDS: &[Command] = &[$(Command {
+            state: State::$state,
+            $($field: $init),*
+        }),*];
+    };
+}
+
+registry! {
+    Power(PowerState) as Power {
+        name: "Power",
+        prefix: "PW",
+        kind: ValueKind::Power,
+        zones: &[Zone::Main],
+        queryable: true,
+        description: "set power",
+        option: Some(CliOption {
+            short: "p",
+            long: "power",
+            hint: "POWER_MODE",
+        }),
+        xml_tag: Some("Power"),
+    },
+    SourceInput(SourceInputState) as SourceInput {
+        name: "SourceInput",
+        prefix: "SI",
+        kind: ValueKind::SourceInput,
+        zones: &[Zone::Main],
+        queryable: true,
+        description: "set source input",
+        option: Some(CliOption {
+            short: "i",
+            long: "input",
+            hint: "SOURCE_INPUT",
+        }),
+        xml_tag: Some("InputFuncSelect"),
+    },
+    MainVolume(u32) as Integer {
+        name: "MainVolume",
+        prefix: "MV",
+        kind: ValueKind::Volume {
+            min: 0,
+            max: MAIN_VOLUME_CAP,
+        },
+        zones: &[Zone::Main],
+        queryable: true,
+        description: "set volume",
+        option: Some(CliOption {
+            short: "v",
+            long: "volume",
+            hint: "VOLUME",
+        }),
+        xml_tag: Some("MasterVolume"),
+    },
+    MaxVolume(u32) as Integer {
+        name: "MaxVolume",
+        prefix: "MVMAX",
+        kind: ValueKind::Volume { min: 0, max: 98 },
+        zones: &[Zone::Main],
+        queryable: true,
+        description: "maximum volume",
+        option: None,
+        // not part of the status page
+        xml_tag: None,
+    },
+}
+
+pub fn commands() -> &'static [Command] {
+    COMMANDS
+}
+
+/// Commands whose state can be queried from a receiver of `model`.
+pub fn queryable_commands(model: &Model) -> impl Iterator<Item = &'static Command> + '_ {
+    COMMANDS
+        .iter()
+        .filter(|c| c.queryable && model.supports(c.state))
+}
+
+pub fn command(state: State) -> &'static Command {
+    COMMANDS
+        .iter()
+        .find(|c| c.state == state)
+        .expect("every state has a command")
+}
+
+/// Finds the command with the longest prefix matching the given line.
+pub fn find_command(line: &str) -> Option<&'static Command> {
+    COMMANDS
+        .iter()
+        .filter(|c| line.starts_with(c.prefix))
+        .max_by_key(|c| c.prefix.len())
+}
+
+pub fn commands_listing() -> String {
+    let mut listing = format!(
+        "{:<6}{:<13}{:<7}{:<6}{:<10}{}\n",
+        "CMD", "NAME", "ZONES", "QUERY", "OPTION", "VALUES"
+    );
+    for command in commands() {
+        listing += &format!("{}\n", command);
+    }
+    listing
+}
+
+#[cfg(test)]
+mod test {
+    use super::{command, commands, commands_listing, find_command, Zone};
+    use crate::error::Error;
+    use crate::model::{default_model, find_model};
+    use crate::state::{PowerState, SetState, SourceInputState, State};
+
+    #[test]
+    fn every_state_has_a_command() {
+        for c in commands() {
+            assert_eq!(c, command(c.state));
+        }
+        assert_eq!(4, commands().len());
+    }
+
+    #[test]
+    fn find_command_prefers_longest_prefix() {
+        assert_eq!(State::MaxVolume, find_command("MVMAX 50").unwrap().state);
+        assert_eq!(State::MainVolume, find_command("MV50").unwrap().state);
+        assert_eq!(State::Power, find_command("PWON").unwrap().state);
+        assert_eq!(None, find_command("PSBAS 50"));
+    }
+
+    #[test]
+    fn parse_input_validates() {
+        let power = command(State::Power);
+        assert!(matches!(
+            power.parse_input("ON", default_model()),
+            Ok(SetState::Power(PowerState::On))
+        ));
+        assert!(matches!(
+            power.parse_input("OFF", default_model()),
+            Err(Error::Input(_))
+        ));
+
+        let input = command(State::SourceInput);
+        assert!(matches!(
+            input.parse_input("DVD", default_model()),
+            Ok(SetState::SourceInput(SourceInputState::Dvd))
+        ));
+
+        let volume = command(State::MainVolume);
+        assert!(matches!(
+            volume.parse_input("50", default_model()),
+            Ok(SetState::MainVolume(500))
+        ));
+        assert!(matches!(
+            volume.parse_input("981", default_model()),
+            Ok(SetState::MainVolume(500))
+        ));
+        let max_volume = command(State::MaxVolume);
+        assert!(matches!(
+            max_volume.parse_input("981", default_model()),
+            Ok(SetState::MaxVolume(980))
+        ));
+        assert!(matches!(
+            volume.parse_input("x", default_model()),
+            Err(Error::ParseInt(_))
+        ));
+    }
+
+    #[test]
+    fn parse_input_respects_model() {
+        let x = find_model("AVR-X").unwrap();
+        let input = command(State::SourceInput);
+        assert!(matches!(
+            input.parse_input("TV", x),
+            Ok(SetState::SourceInput(SourceInputState::TvAudio))
+        ));
+        assert!(matches!(
+            input.parse_input("HEOS", x),
+            Ok(SetState::SourceInput(SourceInputState::Heos))
+        ));
+        assert!(matches!(
+            input.parse_input("NAPSTER", x),
+            Err(Error::Input(e)) if e == "source input NAPSTER is not supported by AVR-X"
+        ));
+        assert!(matches!(
+            input.parse_input("HEOS", default_model()),
+            Err(Error::Input(e)) if e == "source input HEOS is not supported by AVR-1912"
+        ));
+    }
+
+    #[test]
+    fn help_lists_allowed_values() {
+        assert_eq!("set power: ON, STANDBY", command(State::Power).help());
+        assert_eq!("set volume: 0..50", command(State::MainVolume).help());
+    }
+
+    #[test]
+    fn listing_contains_all_commands() {
+        let listing = commands_listing();
+        assert_eq!(5, listing.lines().count());
+        assert!(listing.starts_with("CMD   NAME"));
+        assert!(listing.contains("PW    Power        MAIN   ?     --power   ON, STANDBY\n"));
+        assert!(listing.contains("MVMAX MaxVolume    MAIN   ?               0..98\n"));
+    }
+
+    #[test]
+    fn zone_display() {
+        assert_eq!("MAIN", Zone::Main.to_string());
+    }
+}
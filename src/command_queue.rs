@@ -0,0 +1,353 @@
+use crate::model::Model;
+use crate::state::{PowerState, SetState, State};
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Pauses the receiver needs between commands, otherwise it ignores some.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pacing {
+    pub interval: Duration,
+    // the receiver does not accept commands while it is powering on
+    pub power_on_delay: Duration,
+}
+
+impl Pacing {
+    /// The pause to keep after sending `command`.
+    pub(crate) fn pause(&self, command: &QueuedCommand) -> Duration {
+        if command.powers_on {
+            self.power_on_delay
+        } else {
+            self.interval
+        }
+    }
+}
+
+impl Default for Pacing {
+    fn default() -> Self {
+        Pacing {
+            interval: Duration::from_millis(50),
+            power_on_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A line to send to the receiver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueuedCommand {
+    line: String,
+    // replaces the last queued command with the same key instead of being
+    // sent twice
+    key: Option<State>,
+    powers_on: bool,
+}
+
+impl QueuedCommand {
+    pub fn set(sstate: SetState, model: &Model) -> QueuedCommand {
+        QueuedCommand {
+            line: format!("{}\r", model.encode(&sstate)),
+            key: match sstate {
+                SetState::MainVolume(_) | SetState::MaxVolume(_) => Some(sstate.convert().0),
+                _ => None,
+            },
+            powers_on: sstate == SetState::Power(PowerState::On),
+        }
+    }
+
+    pub fn query(state: State) -> QueuedCommand {
+        QueuedCommand::raw(&format!("{}?", state))
+    }
+
+    pub fn raw(command: &str) -> QueuedCommand {
+        QueuedCommand {
+            line: format!("{}\r", command),
+            key: None,
+            powers_on: false,
+        }
+    }
+
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    pending: VecDeque<(u64, QueuedCommand)>,
+    next: u64,
+    written: u64,
+    // failed writes by sequence, only the latest are kept as commands
+    // which nobody waits for are never asked for
+    failed: BTreeMap<u64, io::Error>,
+    closed: bool,
+}
+
+// number of sequences a failed write is kept for `wait()`
+const FAILED_KEPT: u64 = 64;
+
+#[derive(Default)]
+struct QueueShared {
+    queue: Mutex<Queue>,
+    changed: Condvar,
+}
+
+/// Sends commands from a thread of its own, which keeps the pauses of `Pacing`.
+pub struct CommandQueue {
+    shared: Arc<QueueShared>,
+    thread_handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+type WriteFn = Box<dyn FnMut(&str) -> Result<(), io::Error> + Send>;
+
+fn writer_func_impl(shared: &QueueShared, pacing: Pacing, mut write: WriteFn) {
+    let mut ready_at = Instant::now();
+    let mut queue = shared.queue.lock().unwrap();
+    loop {
+        if queue.pending.is_empty() {
+            if queue.closed {
+                return;
+            }
+            queue = shared.changed.wait(queue).unwrap();
+            continue;
+        }
+        // commands queued meanwhile may still be coalesced
+        let now = Instant::now();
+        if now < ready_at {
+            queue = shared
+                .changed
+                .wait_timeout(queue, ready_at - now)
+                .unwrap()
+                .0;
+            continue;
+        }
+        let (sequence, command) = queue.pending.pop_front().unwrap();
+        drop(queue);
+        let result = write(command.line());
+        ready_at = Instant::now() + pacing.pause(&command);
+        queue = shared.queue.lock().unwrap();
+        queue.written = sequence;
+        if let Err(e) = result {
+            queue.failed.insert(sequence, e);
+            queue.failed = queue
+                .failed
+                .split_off(&sequence.saturating_sub(FAILED_KEPT));
+        }
+        shared.changed.notify_all();
+    }
+}
+
+impl CommandQueue {
+    pub fn new(pacing: Pacing, write: WriteFn) -> CommandQueue {
+        let shared = Arc::new(QueueShared::default());
+        let cloned_shared = shared.clone();
+        let thread_handle = thread::spawn(move || writer_func_impl(&cloned_shared, pacing, write));
+        CommandQueue {
+            shared,
+            thread_handle: Mutex::new(Some(thread_handle)),
+        }
+    }
+
+    /// Queues `command` and returns its sequence number for `wait()`.
+    pub fn push(&self, command: QueuedCommand) -> Result<u64, io::Error> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.closed {
+            return Err(io::Error::from(io::ErrorKind::NotConnected));
+        }
+        // only the last command is replaced, so the order of the commands is
+        // kept, e.g. a volume is not set before the receiver is powered on
+        if let Some((sequence, queued)) = queue.pending.back_mut() {
+            if command.key.is_some() && queued.key == command.key {
+                *queued = command;
+                return Ok(*sequence);
+            }
+        }
+        queue.next += 1;
+        let sequence = queue.next;
+        queue.pending.push_back((sequence, command));
+        self.shared.changed.notify_all();
+        Ok(sequence)
+    }
+
+    /// Waits until the command with `sequence` was written and returns the
+    /// error of writing it.
+    pub fn wait(&self, sequence: u64) -> Result<(), io::Error> {
+        let queue = self.shared.queue.lock().unwrap();
+        let queue = self
+            .shared
+            .changed
+            .wait_while(queue, |queue| queue.written < sequence)
+            .unwrap();
+        match queue.failed.get(&sequence) {
+            // coalesced commands have several callers waiting
+            Some(e) => Err(io::Error::new(e.kind(), e.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends the queued commands and stops the writer thread.
+    pub fn close(&self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+        if let Some(thread_handle) = self.thread_handle.lock().unwrap().take() {
+            if let Err(e) = thread_handle.join() {
+                std::panic::resume_unwind(e);
+            }
+        }
+    }
+}
+
+impl Drop for CommandQueue {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CommandQueue, Pacing, QueuedCommand};
+    use crate::model::default_model;
+    use crate::state::{PowerState, SetState, State};
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    type Written = Arc<Mutex<Vec<(String, Instant)>>>;
+
+    fn create_queue(pacing: Pacing) -> (CommandQueue, Written) {
+        let written = Written::default();
+        let cloned_written = written.clone();
+        let queue = CommandQueue::new(
+            pacing,
+            Box::new(move |line| {
+                cloned_written
+                    .lock()
+                    .unwrap()
+                    .push((line.to_string(), Instant::now()));
+                Ok(())
+            }),
+        );
+        (queue, written)
+    }
+
+    fn lines(written: &Written) -> Vec<String> {
+        written
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(line, _)| line.clone())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_interval_between_commands() -> Result<(), io::Error> {
+        let pacing = Pacing {
+            interval: Duration::from_millis(50),
+            ..Pacing::default()
+        };
+        let (queue, written) = create_queue(pacing);
+        queue.push(QueuedCommand::query(State::Power))?;
+        queue.push(QueuedCommand::set(
+            SetState::MainVolume(200),
+            default_model(),
+        ))?;
+        let last = queue.push(QueuedCommand::raw("PSBAS 50"))?;
+        queue.wait(last)?;
+
+        assert_eq!(vec!["PW?\r", "MV20\r", "PSBAS 50\r"], lines(&written));
+        let written = written.lock().unwrap();
+        for pair in written.windows(2) {
+            assert!(pair[1].1 - pair[0].1 >= pacing.interval);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn waits_longer_after_power_on() -> Result<(), io::Error> {
+        let pacing = Pacing {
+            interval: Duration::ZERO,
+            power_on_delay: Duration::from_millis(200),
+        };
+        let (queue, written) = create_queue(pacing);
+        queue.push(QueuedCommand::set(
+            SetState::Power(PowerState::On),
+            default_model(),
+        ))?;
+        let last = queue.push(QueuedCommand::query(State::Power))?;
+        queue.wait(last)?;
+
+        let written = written.lock().unwrap();
+        assert_eq!(2, written.len());
+        assert!(written[1].1 - written[0].1 >= pacing.power_on_delay);
+        Ok(())
+    }
+
+    #[test]
+    fn coalesces_queued_volumes() -> Result<(), io::Error> {
+        let pacing = Pacing {
+            interval: Duration::from_millis(200),
+            ..Pacing::default()
+        };
+        let (queue, written) = create_queue(pacing);
+        queue.push(QueuedCommand::query(State::Power))?;
+        let first = queue.push(QueuedCommand::set(
+            SetState::MainVolume(100),
+            default_model(),
+        ))?;
+        let last = queue.push(QueuedCommand::set(
+            SetState::MainVolume(300),
+            default_model(),
+        ))?;
+        assert_eq!(first, last);
+        queue.close();
+
+        assert_eq!(vec!["PW?\r", "MV30\r"], lines(&written));
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_order_of_volumes_around_other_commands() -> Result<(), io::Error> {
+        let pacing = Pacing {
+            interval: Duration::from_millis(200),
+            power_on_delay: Duration::ZERO,
+        };
+        let (queue, written) = create_queue(pacing);
+        queue.push(QueuedCommand::query(State::Power))?;
+        for sstate in [
+            SetState::MainVolume(100),
+            SetState::Power(PowerState::On),
+            SetState::MainVolume(300),
+        ] {
+            queue.push(QueuedCommand::set(sstate, default_model()))?;
+        }
+        queue.close();
+
+        assert_eq!(vec!["PW?\r", "MV10\r", "PWON\r", "MV30\r"], lines(&written));
+        Ok(())
+    }
+
+    #[test]
+    fn reports_write_error_to_its_caller_only() -> Result<(), io::Error> {
+        let queue = CommandQueue::new(
+            Pacing {
+                interval: Duration::ZERO,
+                ..Pacing::default()
+            },
+            Box::new(|line| match line {
+                "PW?\r" => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+                _ => Ok(()),
+            }),
+        );
+        let failing = queue.push(QueuedCommand::query(State::Power))?;
+        let other = queue.push(QueuedCommand::query(State::MainVolume))?;
+        assert!(queue.wait(other).is_ok());
+        assert_eq!(
+            io::ErrorKind::BrokenPipe,
+            queue.wait(failing).unwrap_err().kind()
+        );
+        let later = queue.push(QueuedCommand::query(State::MainVolume))?;
+        assert!(queue.wait(later).is_ok());
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+use crate::command::queryable_commands;
+use crate::denon_connection::{Change, ConnectionEvent, Health, Received};
+use crate::error::Error;
+use crate::model::Model;
+use crate::state::{SetState, State, StateValue};
+use std::io;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+/// Operations offered by every transport to the receiver.
+pub trait Connection: Send + Sync {
+    /// Another handle, which may be used by another thread.
+    fn clone_box(&self) -> Box<dyn Connection>;
+    fn get(&mut self, state: State) -> Result<StateValue, Error>;
+    /// How long `get()` waits for the answer of the receiver.
+    fn query_timeout(&self) -> Duration;
+    /// Like `get()`, but queries the receiver if the value is older than
+    /// `max_age`. Transports without a cache always query.
+    fn get_fresh(&mut self, state: State, _max_age: Duration) -> Result<StateValue, Error> {
+        self.get(state)
+    }
+    /// Returns the values of all `states`, which may be fetched at once.
+    fn query_many(&mut self, states: &[State]) -> Result<Vec<StateValue>, Error> {
+        states.iter().map(|state| self.get(*state)).collect()
+    }
+    /// Queries all states supported by the model again.
+    fn refresh_all(&mut self) -> Result<Vec<StateValue>, Error> {
+        let states: Vec<State> = queryable_commands(self.model())
+            .map(|c| c.state)
+            .filter(|state| self.supports(*state))
+            .collect();
+        self.query_many(&states)
+    }
+    /// Values older than `max_age` are queried again, `None` keeps them
+    /// until the receiver reports a change.
+    fn set_max_age(&mut self, _max_age: Option<Duration>) {}
+    fn set(&mut self, sstate: SetState) -> Result<(), io::Error>;
+    /// Sets `sstate` and waits at most `timeout` until the receiver reports it.
+    fn set_confirmed(&mut self, sstate: SetState, timeout: Duration) -> Result<StateValue, Error>;
+    /// Sends `command` unmodified and returns the replies of the receiver.
+    fn send_raw(&mut self, command: &str, wait: Duration) -> Result<Vec<String>, io::Error>;
+    /// How long ago the value of `state` was received, if it is cached.
+    fn age(&self, state: State) -> Option<Duration> {
+        self.received(state).map(|received| received.age())
+    }
+    /// The last received value of `state` with its age and origin, if it is
+    /// cached.
+    fn received(&self, _state: State) -> Option<Received> {
+        None
+    }
+    fn model(&self) -> &'static Model;
+    /// Whether the value of `state` can be read over this connection.
+    fn supports(&self, state: State) -> bool {
+        self.model().supports(state)
+    }
+    /// Number of values received from the receiver which could not be parsed.
+    fn parse_errors(&self) -> usize;
+    /// Receives the changes of `states`, or of all states for `None`, which
+    /// only transports staying connected can report.
+    fn subscribe(&self, _states: Option<&[State]>) -> Result<Receiver<Change>, io::Error> {
+        Err(unsupported("subscriptions"))
+    }
+    /// Receives an event whenever the connection is lost or established again.
+    fn events(&self) -> Result<Receiver<ConnectionEvent>, io::Error> {
+        Err(unsupported("connection events"))
+    }
+    /// Transports connecting for every request are always connected.
+    fn health(&self) -> Health {
+        Health::Connected
+    }
+    /// Shuts the connection down.
+    fn stop(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} need a telnet connection", what),
+    )
+}
@@ -1,222 +1,970 @@
+use crate::charset::decode_text;
+use crate::codec::LineCodec;
+use crate::command::queryable_commands;
+use crate::command_queue::{CommandQueue, Pacing, QueuedCommand};
+use crate::connection::Connection;
+use crate::error::Error;
 use crate::logger::Logger;
+use crate::model::Model;
 use crate::parse::parse;
 use crate::state::{SetState, State, StateValue};
-use crate::stream::{ConnectionStream, ReadStream};
-use std::collections::HashMap;
+use crate::stream::{ConnectionStream, ReadStream, StreamFactory};
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
 use std::io::{self, ErrorKind, Write};
 use std::panic;
-use std::rc::Rc;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const ESHUTDOWN: i32 = 108;
+// how long get() waits for the answer of the receiver by default
+pub const QUERY_TIMEOUT: Duration = Duration::from_millis(500);
 
 pub fn write_string(stream: &mut dyn Write, input: &str) -> Result<(), std::io::Error> {
     stream.write_all(input.as_bytes())
 }
 
-fn write_state(stream: &mut dyn Write, state: SetState) -> Result<(), io::Error> {
-    write_string(stream, format!("{}\r", state).as_str())
+#[cfg(test)]
+fn shutdown_error() -> io::Error {
+    io::Error::from_raw_os_error(ESHUTDOWN)
 }
 
-fn write_query(stream: &mut dyn Write, state: State) -> Result<(), io::Error> {
-    write_string(stream, format!("{}?\r", state).as_str())
+pub fn decode_line(line: &[u8]) -> String {
+    decode_text(line).trim().to_owned()
 }
 
-pub fn read(stream: &dyn ReadStream, lines: u8) -> Result<Vec<String>, std::io::Error> {
-    let mut result = Vec::<String>::new();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Disconnected,
+    Reconnected,
+}
 
-    // guarantee to read a full line. check that read content ends with \r
-    while (lines as usize) != result.len() {
-        let mut buffer = [0; 100];
-        let read_bytes;
-        match stream.peekly(&mut buffer) {
-            Ok(rb) => read_bytes = rb,
-            Err(e) => {
-                if result.is_empty() {
-                    return Err(e);
-                } else {
-                    break;
-                }
-            }
+/// Delays between attempts to reconnect, which double up to `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
         }
+    }
+}
 
-        if 0 == read_bytes {
-            // shutdown called
-            if result.is_empty() {
-                return Err(io::Error::from_raw_os_error(ESHUTDOWN));
-            } else {
-                break;
-            }
+impl Backoff {
+    fn next(&self, delay: Duration) -> Duration {
+        min(delay.saturating_mul(2), self.max)
+    }
+}
+
+/// Whether a value answered a query or was sent by the receiver on its own,
+/// e.g. after the volume was turned with the remote control.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    Reply,
+    Event,
+}
+
+/// A value received from the receiver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Received {
+    pub value: StateValue,
+    pub updated: Instant,
+    pub origin: Origin,
+}
+
+impl Received {
+    pub fn age(&self) -> Duration {
+        self.updated.elapsed()
+    }
+}
+
+/// Records `sstate` as received just now. It is a reply if its state is
+/// removed from `awaiting`, otherwise an event.
+pub(crate) fn record(
+    sstate: SetState,
+    states: &mut HashMap<State, Received>,
+    awaiting: &mut HashSet<State>,
+) -> (State, Received) {
+    let (state, value) = sstate.convert();
+    let origin = match awaiting.remove(&state) {
+        true => Origin::Reply,
+        false => Origin::Event,
+    };
+    let received = Received {
+        value,
+        updated: Instant::now(),
+        origin,
+    };
+    states.insert(state, received);
+    (state, received)
+}
+
+/// Splits the data received from the receiver into lines and parses them.
+/// Used by the reader thread as well as by the async reader task.
+pub(crate) struct LineHandler {
+    codec: LineCodec,
+    model: &'static Model,
+}
+
+impl LineHandler {
+    pub(crate) fn new(model: &'static Model) -> LineHandler {
+        LineHandler {
+            codec: LineCodec::default(),
+            model,
         }
+    }
 
-        // search for first \r in buffer
-        let first_cariage_return = buffer[0..read_bytes]
-            .iter()
-            .position(|&c| '\r' == (c as char));
+    pub(crate) fn fill(&mut self, stream: &dyn ReadStream) -> Result<usize, io::Error> {
+        self.codec.fill(stream)
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn extend(&mut self, data: &[u8]) {
+        self.codec.extend(data);
+    }
+
+    /// Returns the complete lines received so far and the values parsed
+    /// from them. Lines which cannot be parsed are logged and counted.
+    pub(crate) fn take_lines(
+        &mut self,
+        parse_errors: &AtomicUsize,
+        logger: &dyn Logger,
+    ) -> (Vec<String>, Vec<SetState>) {
+        let discarded = self.codec.discarded_lines();
+        let lines: Vec<String> = std::iter::from_fn(|| self.codec.next_line())
+            .map(|line| decode_line(&line))
+            .collect();
+        if self.codec.discarded_lines() > discarded {
+            parse_errors.fetch_add(1, Ordering::Relaxed);
+            logger.log("skipping line: too long without line end");
+        }
+        let parsed = parse_response(&lines, self.model, parse_errors, logger);
+        (lines, parsed)
+    }
+}
+
+/// A state which the receiver reported with another value than before, e.g.
+/// after the volume was turned with the remote control.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change {
+    pub state: State,
+    // None for the first value after connecting
+    pub old: Option<StateValue>,
+    pub new: StateValue,
+    pub time: Instant,
+}
+
+struct Subscriber {
+    // None receives the changes of all states
+    states: Option<HashSet<State>>,
+    sender: Sender<Change>,
+}
+
+#[derive(Default)]
+struct Subscriptions {
+    // the values the changes refer to, which survive clearing the state
+    last: HashMap<State, StateValue>,
+    subscribers: Vec<Subscriber>,
+}
+
+impl Subscriptions {
+    fn update(&mut self, state: State, value: StateValue, time: Instant) {
+        let old = self.last.insert(state, value);
+        if old == Some(value) {
+            return;
+        }
+        let change = Change {
+            state,
+            old,
+            new: value,
+            time,
+        };
+        self.subscribers
+            .retain(|subscriber| match &subscriber.states {
+                Some(states) if !states.contains(&state) => true,
+                _ => subscriber.sender.send(change).is_ok(),
+            });
+    }
+}
+
+/// State of the connection as seen by its reader thread.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Health {
+    Connected,
+    // the connection was lost and is established again
+    Reconnecting,
+    // the reader thread has ended with the given cause, nothing is received anymore
+    Lost(String),
+}
+
+// data shared between DenonConnection and its reader thread
+struct Shared {
+    model: &'static Model,
+    to_receiver: Arc<Mutex<Box<dyn ConnectionStream>>>,
+    commands: CommandQueue,
+    state: Mutex<HashMap<State, Received>>,
+    // states whose query was sent, but not answered yet
+    awaiting: Mutex<HashSet<State>>,
+    // signalled whenever a state was received or the connection was lost
+    state_changed: Condvar,
+    connected: AtomicBool,
+    // why the reader thread has ended
+    lost: Mutex<Option<String>>,
+    // states queried by the user, which are queried again after reconnecting
+    queried: Mutex<HashSet<State>>,
+    parse_errors: AtomicUsize,
+    // every received line is appended while a raw command waits for its answer
+    captured_lines: Mutex<Option<Vec<String>>>,
+    // held by the raw command capturing lines, clones have to wait for it
+    capturing: Mutex<()>,
+    stopped: Mutex<bool>,
+    stop_signal: Condvar,
+    listeners: Mutex<Vec<Sender<ConnectionEvent>>>,
+    subscriptions: Mutex<Subscriptions>,
+}
+
+impl Shared {
+    fn new(
+        to_receiver: Box<dyn ConnectionStream>,
+        model: &'static Model,
+        pacing: Pacing,
+    ) -> Shared {
+        let to_receiver = Arc::new(Mutex::new(to_receiver));
+        let cloned_to_receiver = to_receiver.clone();
+        let commands = CommandQueue::new(
+            pacing,
+            Box::new(move |line| write_string(&mut *cloned_to_receiver.lock().unwrap(), line)),
+        );
+        Shared {
+            model,
+            to_receiver,
+            commands,
+            state: Mutex::default(),
+            awaiting: Mutex::default(),
+            state_changed: Condvar::new(),
+            connected: AtomicBool::new(true),
+            lost: Mutex::default(),
+            queried: Mutex::default(),
+            parse_errors: AtomicUsize::default(),
+            captured_lines: Mutex::default(),
+            capturing: Mutex::default(),
+            stopped: Mutex::new(false),
+            stop_signal: Condvar::new(),
+            listeners: Mutex::default(),
+            subscriptions: Mutex::default(),
+        }
+    }
+
+    fn stop(&self) -> Result<(), io::Error> {
+        // the stream is locked first, so a reconnect cannot replace it unnoticed
+        let to_receiver = self.to_receiver.lock().unwrap();
+        *self.stopped.lock().unwrap() = true;
+        self.stop_signal.notify_all();
+        to_receiver.shutdownly()
+    }
+
+    fn set_connected(&self, connected: bool) {
+        let _state = self.state.lock().unwrap();
+        self.connected.store(connected, Ordering::Relaxed);
+        self.state_changed.notify_all();
+    }
+
+    /// Records why the reader thread has ended and wakes up all waiters.
+    fn lose(&self, cause: String) {
+        *self.lost.lock().unwrap() = Some(cause);
+        self.set_connected(false);
+    }
+
+    /// Fails with the cause once the reader thread has ended.
+    fn check_alive(&self) -> Result<(), io::Error> {
+        match self.lost.lock().unwrap().as_ref() {
+            Some(cause) => Err(io::Error::new(
+                ErrorKind::NotConnected,
+                format!("connection lost: {}", cause),
+            )),
+            None => Ok(()),
+        }
+    }
 
-        if first_cariage_return.is_none() {
-            break;
+    /// Like `check_alive()`, but fails as well while reconnecting.
+    fn check_connected(&self) -> Result<(), io::Error> {
+        self.check_alive()?;
+        match self.connected.load(Ordering::Relaxed) {
+            true => Ok(()),
+            false => Err(io::Error::from(ErrorKind::NotConnected)),
         }
+    }
 
-        // include cariage return in read_exact()
-        let bytes_to_extract = first_cariage_return.unwrap() + 1;
+    /// Waits until a value of `state` was received. Returns `None` once
+    /// `deadline` has passed.
+    fn wait_for_value(
+        &self,
+        state: State,
+        deadline: Instant,
+    ) -> Result<Option<StateValue>, io::Error> {
+        Ok(self.wait_for_values(&[state], deadline)?[0])
+    }
 
-        // do not add \r to string
-        if let Ok(tmp) = std::str::from_utf8(&buffer[0..first_cariage_return.unwrap()]) {
-            result.push(tmp.trim().to_owned());
+    /// Waits until values of all `states` were received. Missing values are
+    /// `None` once `deadline` has passed.
+    fn wait_for_values(
+        &self,
+        states: &[State],
+        deadline: Instant,
+    ) -> Result<Vec<Option<StateValue>>, io::Error> {
+        let locked_state = self.state.lock().unwrap();
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let (locked_state, _) = self
+            .state_changed
+            .wait_timeout_while(locked_state, timeout, |locked_state| {
+                !states.iter().all(|state| locked_state.contains_key(state))
+                    && self.connected.load(Ordering::Relaxed)
+            })
+            .unwrap();
+        let values: Vec<Option<StateValue>> = states
+            .iter()
+            .map(|state| locked_state.get(state).map(|received| received.value))
+            .collect();
+        if values.contains(&None) {
+            self.check_connected()?;
         }
+        Ok(values)
+    }
+
+    fn is_stopped(&self) -> bool {
+        *self.stopped.lock().unwrap()
+    }
 
-        stream.read_exactly(&mut buffer[0..bytes_to_extract])?;
+    /// Waits for `timeout` and returns whether the connection was stopped.
+    fn wait_for_stop(&self, timeout: Duration) -> bool {
+        let stopped = self.stopped.lock().unwrap();
+        let (stopped, _) = self
+            .stop_signal
+            .wait_timeout_while(stopped, timeout, |stopped| !*stopped)
+            .unwrap();
+        *stopped
     }
 
-    Ok(result)
+    /// Replaces the stream to the receiver and queries the known states
+    /// again. Returns false if the connection was stopped meanwhile.
+    fn replace_stream(&self, stream: Box<dyn ConnectionStream>, logger: &dyn Logger) -> bool {
+        let mut to_receiver = self.to_receiver.lock().unwrap();
+        if *self.stopped.lock().unwrap() {
+            let _ = stream.shutdownly();
+            return false;
+        }
+        *to_receiver = stream;
+        // the receiver may have changed while being unreachable
+        self.state.lock().unwrap().clear();
+        for state in self.queried.lock().unwrap().iter() {
+            if let Err(e) = self.commands.push(QueuedCommand::query(*state)) {
+                logger.log(&format!("querying {} failed: {}", state, e));
+            }
+        }
+        true
+    }
+
+    fn notify(&self, event: ConnectionEvent) {
+        self.listeners
+            .lock()
+            .unwrap()
+            .retain(|listener| listener.send(event).is_ok());
+    }
 }
 
 fn thread_func_impl(
     stream: &dyn ReadStream,
-    state: Arc<Mutex<HashMap<State, StateValue>>>,
+    shared: &Shared,
+    logger: &dyn Logger,
 ) -> Result<(), std::io::Error> {
+    let mut handler = LineHandler::new(shared.model);
     loop {
-        match read(stream, 1) {
-            Ok(status_update) => {
-                let parsed_response = parse_response(&status_update);
-                let mut locked_state = state.lock().unwrap();
-                for sstate in parsed_response {
-                    let (state, value) = sstate.convert();
-                    locked_state.insert(state, value);
-                }
+        let (status_update, parsed_response) = handler.take_lines(&shared.parse_errors, logger);
+        if !status_update.is_empty() {
+            if let Some(captured) = shared.captured_lines.lock().unwrap().as_mut() {
+                captured.extend(status_update);
+            }
+            let mut locked_state = shared.state.lock().unwrap();
+            let mut awaiting = shared.awaiting.lock().unwrap();
+            let mut subscriptions = shared.subscriptions.lock().unwrap();
+            for sstate in parsed_response {
+                let (state, received) = record(sstate, &mut locked_state, &mut awaiting);
+                subscriptions.update(state, received.value, received.updated);
             }
+            shared.state_changed.notify_all();
+        }
+
+        match handler.fill(stream) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
             // check for timeout error -> continue on timeout error, else abort
             Err(e) => {
-                if ErrorKind::TimedOut != e.kind() && ErrorKind::WouldBlock != e.kind() {
-                    if e.raw_os_error() != Some(ESHUTDOWN) {
-                        return Err(e);
-                    }
+                // a stream shut down by stop() ends the thread cleanly
+                if e.raw_os_error() == Some(ESHUTDOWN) {
                     return Ok(());
                 }
+                if ErrorKind::TimedOut != e.kind() && ErrorKind::WouldBlock != e.kind() {
+                    return Err(e);
+                }
             }
         }
     }
 }
 
-fn parse_response(response: &[String]) -> Vec<SetState> {
-    return response.iter().filter_map(|x| parse(x.as_str())).collect();
+/// Runs the reader thread function `reader` and records why it has ended, so
+/// callers get the cause instead of outdated values.
+fn supervise(
+    shared: &Shared,
+    reader: impl FnOnce() -> Result<(), io::Error>,
+) -> Result<(), io::Error> {
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(reader));
+    let cause = match &result {
+        _ if shared.is_stopped() => String::from("stopped"),
+        Ok(Ok(())) => String::from("closed by receiver"),
+        Ok(Err(e)) => e.to_string(),
+        Err(_) => String::from("reader thread panicked"),
+    };
+    shared.lose(cause);
+    result.unwrap_or_else(|e| panic::resume_unwind(e))
 }
 
-pub struct DenonConnection {
-    state: Arc<Mutex<HashMap<State, StateValue>>>,
-    to_receiver: Box<dyn ConnectionStream>,
+/// Keeps reading from the receiver and opens a new connection with `factory`
+/// whenever the current one is lost.
+fn reconnecting_thread_func_impl(
+    mut stream: Box<dyn ReadStream>,
+    factory: &StreamFactory,
+    backoff: Backoff,
+    shared: &Shared,
+    logger: &dyn Logger,
+) -> Result<(), io::Error> {
+    loop {
+        let result = thread_func_impl(stream.as_ref(), shared, logger);
+        if shared.is_stopped() {
+            return result;
+        }
+        match result {
+            Ok(()) => logger.log("disconnected from receiver"),
+            Err(e) => logger.log(&format!("disconnected from receiver: {}", e)),
+        }
+        shared.set_connected(false);
+        shared.notify(ConnectionEvent::Disconnected);
+
+        let mut delay = backoff.initial;
+        stream = loop {
+            if shared.wait_for_stop(delay) {
+                return Ok(());
+            }
+            match factory().and_then(|s| Ok((s.get_readstream()?, s))) {
+                Ok((read_stream, to_receiver)) => {
+                    if !shared.replace_stream(to_receiver, logger) {
+                        return Ok(());
+                    }
+                    break read_stream;
+                }
+                Err(e) => logger.log(&format!("reconnecting failed: {}", e)),
+            }
+            delay = backoff.next(delay);
+        };
+        logger.log("reconnected to receiver");
+        shared.set_connected(true);
+        shared.notify(ConnectionEvent::Reconnected);
+    }
+}
+
+fn parse_response(
+    response: &[String],
+    model: &'static Model,
+    parse_errors: &AtomicUsize,
+    logger: &dyn Logger,
+) -> Vec<SetState> {
+    response
+        .iter()
+        .filter_map(|x| match parse(x.as_str(), model) {
+            Ok(sstate) => sstate,
+            Err(e) => {
+                parse_errors.fetch_add(1, Ordering::Relaxed);
+                logger.log(&format!("skipping line: {}", e));
+                None
+            }
+        })
+        .collect()
+}
+
+// stops the reader thread when the last clone of a connection is dropped
+struct ReaderThread {
+    shared: Arc<Shared>,
     thread_handle: Option<JoinHandle<Result<(), io::Error>>>,
-    logger: Rc<dyn Logger>,
+    logger: Arc<dyn Logger>,
+}
+
+impl Drop for ReaderThread {
+    fn drop(&mut self) {
+        // queued commands are sent before the connection is shut down
+        self.shared.commands.close();
+        let _ = self.shared.stop();
+        let thread_result = self
+            .thread_handle
+            .take()
+            .expect("Non running thread is a bug")
+            .join();
+        match thread_result {
+            Ok(result) => {
+                if let Err(e) = result {
+                    self.logger.log(&format!("got error: {}", e));
+                }
+            }
+            Err(e) => panic::resume_unwind(e),
+        }
+    }
+}
+
+/// A connection to the receiver. Clones share the same session, so it can
+/// be used from several threads at once.
+#[derive(Clone)]
+pub struct DenonConnection {
+    shared: Arc<Shared>,
+    // older values are queried again, set per clone
+    max_age: Option<Duration>,
+    query_timeout: Duration,
+    // only held for stopping the reader thread after the last clone
+    _reader: Arc<ReaderThread>,
 }
 
 impl DenonConnection {
+    #[cfg(test)]
     pub fn new(
         to_receiver: Box<dyn ConnectionStream>,
-        logger: Rc<dyn Logger>,
+        logger: Arc<dyn Logger>,
+        model: &'static Model,
+    ) -> Result<DenonConnection, io::Error> {
+        DenonConnection::with_pacing(to_receiver, logger, model, Pacing::default())
+    }
+
+    pub fn with_pacing(
+        to_receiver: Box<dyn ConnectionStream>,
+        logger: Arc<dyn Logger>,
+        model: &'static Model,
+        pacing: Pacing,
     ) -> Result<DenonConnection, io::Error> {
-        let state = Arc::new(Mutex::new(HashMap::new()));
-        let cloned_state = state.clone();
         let s2 = to_receiver.get_readstream()?;
+        let shared = Arc::new(Shared::new(to_receiver, model, pacing));
+        let cloned_shared = shared.clone();
+        let cloned_logger = logger.clone();
 
-        let threadhandle = thread::spawn(move || thread_func_impl(s2.as_ref(), cloned_state));
+        let threadhandle = thread::spawn(move || {
+            supervise(&cloned_shared, || {
+                thread_func_impl(s2.as_ref(), &cloned_shared, cloned_logger.as_ref())
+            })
+        });
 
-        Ok(DenonConnection {
-            state,
-            to_receiver,
-            thread_handle: Some(threadhandle),
+        Ok(DenonConnection::with_reader(shared, threadhandle, logger))
+    }
+
+    fn with_reader(
+        shared: Arc<Shared>,
+        thread_handle: JoinHandle<Result<(), io::Error>>,
+        logger: Arc<dyn Logger>,
+    ) -> DenonConnection {
+        let reader = Arc::new(ReaderThread {
+            shared: shared.clone(),
+            thread_handle: Some(thread_handle),
             logger,
-        })
+        });
+        DenonConnection {
+            shared,
+            max_age: None,
+            query_timeout: QUERY_TIMEOUT,
+            _reader: reader,
+        }
     }
 
-    pub fn get(&mut self, op: State) -> Result<StateValue, io::Error> {
-        // should first check if the requested op is present in state
-        // if it is not present it should send the request to the thread and wait until completion
-        {
-            let locked_state = self.state.lock().unwrap();
-            if let Some(received_state) = locked_state.get(&op) {
-                return Ok(*received_state);
-            }
+    /// Connects with `factory` and connects again with increasing delays
+    /// whenever the connection is lost, e.g. while the receiver reboots.
+    pub fn reconnecting(
+        factory: StreamFactory,
+        backoff: Backoff,
+        logger: Arc<dyn Logger>,
+        model: &'static Model,
+        pacing: Pacing,
+    ) -> Result<DenonConnection, io::Error> {
+        let to_receiver = factory()?;
+        let s2 = to_receiver.get_readstream()?;
+        let shared = Arc::new(Shared::new(to_receiver, model, pacing));
+        let cloned_shared = shared.clone();
+        let cloned_logger = logger.clone();
+
+        let threadhandle = thread::spawn(move || {
+            supervise(&cloned_shared, || {
+                reconnecting_thread_func_impl(
+                    s2,
+                    &factory,
+                    backoff,
+                    &cloned_shared,
+                    cloned_logger.as_ref(),
+                )
+            })
+        });
+
+        Ok(DenonConnection::with_reader(shared, threadhandle, logger))
+    }
+
+    /// Returns a channel, which receives an event whenever the connection
+    /// is lost or established again.
+    pub fn events(&self) -> Receiver<ConnectionEvent> {
+        let (sender, receiver) = channel();
+        self.shared.listeners.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Returns a channel, which receives a `Change` whenever the receiver
+    /// reports a new value of any state.
+    pub fn subscribe(&self) -> Receiver<Change> {
+        self.add_subscriber(None)
+    }
+
+    /// Like `subscribe()`, but only for the changes of `states`.
+    pub fn subscribe_to(&self, states: &[State]) -> Receiver<Change> {
+        self.add_subscriber(Some(states.iter().copied().collect()))
+    }
+
+    fn add_subscriber(&self, states: Option<HashSet<State>>) -> Receiver<Change> {
+        let (sender, receiver) = channel();
+        let subscriber = Subscriber { states, sender };
+        self.shared
+            .subscriptions
+            .lock()
+            .unwrap()
+            .subscribers
+            .push(subscriber);
+        receiver
+    }
+
+    /// Whether the receiver is connected, or why the connection was lost.
+    pub fn health(&self) -> Health {
+        if let Some(cause) = self.shared.lost.lock().unwrap().as_ref() {
+            return Health::Lost(cause.clone());
         }
-        write_query(&mut self.to_receiver, op)?;
-        for _ in 0..50 {
-            thread::sleep(Duration::from_millis(10));
-            let locked_state = self.state.lock().unwrap();
-            if let Some(state) = locked_state.get(&op) {
-                return Ok(*state);
+        match self.shared.connected.load(Ordering::Relaxed) {
+            true => Health::Connected,
+            false => Health::Reconnecting,
+        }
+    }
+
+    pub fn get(&mut self, op: State) -> Result<StateValue, Error> {
+        self.get_timeout(op, self.query_timeout)
+    }
+
+    /// Returns the last received value of `op`. If there is none or it is
+    /// older than the maximum age, the receiver is queried and its answer is
+    /// awaited at most `timeout`.
+    pub fn get_timeout(&mut self, op: State, timeout: Duration) -> Result<StateValue, Error> {
+        Ok(self.query_many(&[op], timeout)?[0])
+    }
+
+    /// Like `get()`, but queries the receiver if the value is older than
+    /// `max_age`.
+    pub fn get_fresh(&mut self, op: State, max_age: Duration) -> Result<StateValue, Error> {
+        Ok(self.query_fresh(&[op], Some(max_age), self.query_timeout)?[0])
+    }
+
+    /// Like `get_timeout()` for several states, whose queries are sent in
+    /// one burst, so their answers are awaited at the same time.
+    pub fn query_many(
+        &mut self,
+        states: &[State],
+        timeout: Duration,
+    ) -> Result<Vec<StateValue>, Error> {
+        self.query_fresh(states, self.max_age, timeout)
+    }
+
+    fn query_fresh(
+        &mut self,
+        states: &[State],
+        max_age: Option<Duration>,
+        timeout: Duration,
+    ) -> Result<Vec<StateValue>, Error> {
+        // the values of a lost connection are outdated
+        self.shared.check_connected()?;
+        let missing: Vec<State> = {
+            let mut locked_state = self.shared.state.lock().unwrap();
+            let missing: Vec<State> = states
+                .iter()
+                .filter(|state| match locked_state.get(state) {
+                    Some(received) => max_age.is_some_and(|max_age| received.age() > max_age),
+                    None => true,
+                })
+                .copied()
+                .collect();
+            for state in &missing {
+                locked_state.remove(state);
+            }
+            missing
+        };
+        if let Some(last) = missing.last() {
+            self.shared.queried.lock().unwrap().extend(&missing);
+            self.shared.awaiting.lock().unwrap().extend(&missing);
+            for state in &missing[..missing.len() - 1] {
+                self.shared.commands.push(QueuedCommand::query(*state))?;
             }
+            let sequence = self.shared.commands.push(QueuedCommand::query(*last))?;
+            // the timeout starts once the last query was sent
+            self.shared.commands.wait(sequence)?;
         }
-        Ok(StateValue::Unknown)
+        let values = self
+            .shared
+            .wait_for_values(states, Instant::now() + timeout)?;
+        states
+            .iter()
+            .zip(values)
+            .map(|(state, value)| value.ok_or(Error::Timeout(*state)))
+            .collect()
+    }
+
+    /// Forgets all received states and queries those supported by the model
+    /// again, e.g. after the receiver was operated by its remote control.
+    pub fn refresh_all(&mut self) -> Result<Vec<StateValue>, Error> {
+        let states: Vec<State> = queryable_commands(self.shared.model)
+            .map(|c| c.state)
+            .collect();
+        self.shared.state.lock().unwrap().clear();
+        self.query_many(&states, self.query_timeout)
+    }
+
+    /// Values older than `max_age` are queried again by this handle, `None` keeps them
+    /// until the receiver reports a change.
+    pub fn set_max_age(&mut self, max_age: Option<Duration>) {
+        self.max_age = max_age;
+    }
+
+    /// How long `get()` waits for the answer of the receiver.
+    pub fn set_query_timeout(&mut self, query_timeout: Duration) {
+        self.query_timeout = query_timeout;
+    }
+
+    pub fn query_timeout(&self) -> Duration {
+        self.query_timeout
+    }
+
+    /// Returns the last received value of `state` with its age and origin.
+    pub fn received(&self, state: State) -> Option<Received> {
+        self.shared.state.lock().unwrap().get(&state).copied()
+    }
+
+    /// Sends `command` unmodified to the receiver and returns all lines
+    /// received until `wait` has passed.
+    pub fn send_raw(&mut self, command: &str, wait: Duration) -> Result<Vec<String>, io::Error> {
+        self.shared.check_alive()?;
+        let _capturing = self.shared.capturing.lock().unwrap();
+        *self.shared.captured_lines.lock().unwrap() = Some(Vec::new());
+        let written = self
+            .shared
+            .commands
+            .push(QueuedCommand::raw(command))
+            .and_then(|sequence| self.shared.commands.wait(sequence));
+        if written.is_ok() {
+            thread::sleep(wait);
+        }
+        let captured = self.shared.captured_lines.lock().unwrap().take();
+        written.map(|_| captured.unwrap_or_default())
+    }
+
+    pub fn model(&self) -> &'static Model {
+        self.shared.model
+    }
+
+    /// Number of lines received from the receiver which could not be parsed.
+    pub fn parse_errors(&self) -> usize {
+        self.shared.parse_errors.load(Ordering::Relaxed)
     }
 
+    /// Shuts the connection down for all clones.
     pub fn stop(&mut self) -> Result<(), io::Error> {
-        self.to_receiver.shutdownly()
+        self.shared.stop()
     }
 
+    /// Queues `sstate`, which is sent after the pause required by the receiver.
     pub fn set(&mut self, sstate: SetState) -> Result<(), io::Error> {
-        write_state(&mut self.to_receiver, sstate)
+        self.shared.check_alive()?;
+        self.shared
+            .commands
+            .push(QueuedCommand::set(sstate, self.shared.model))
+            .map(|_| ())
     }
-}
 
-impl Drop for DenonConnection {
-    fn drop(&mut self) {
-        let _ = self.stop();
-        let thread_result = self
-            .thread_handle
-            .take()
-            .expect("Non running thread is a bug")
-            .join();
-        match thread_result {
-            Ok(result) => {
-                if let Err(e) = result {
-                    self.logger.log(&format!("got error: {}", e));
-                }
-            }
-            Err(e) => panic::resume_unwind(e),
+    /// Sets `sstate` and waits at most `timeout` until the receiver echoes
+    /// the new state.
+    pub fn set_confirmed(
+        &mut self,
+        sstate: SetState,
+        timeout: Duration,
+    ) -> Result<StateValue, Error> {
+        let state = sstate.convert().0;
+        let expected = self.shared.model.echo(&sstate);
+        // forget the old value, so only the echo is seen
+        self.shared.state.lock().unwrap().remove(&state);
+        self.shared.awaiting.lock().unwrap().insert(state);
+        self.shared.check_alive()?;
+        let sequence = self
+            .shared
+            .commands
+            .push(QueuedCommand::set(sstate, self.shared.model))?;
+        // the timeout starts once the command was sent, the pacing may hold
+        // it back for a while, e.g. after powering on
+        self.shared.commands.wait(sequence)?;
+        match self
+            .shared
+            .wait_for_value(state, Instant::now() + timeout)?
+        {
+            Some(value) if value == expected => Ok(value),
+            Some(value) => Err(Error::Rejected(sstate, value)),
+            None => Err(Error::Unconfirmed(sstate)),
         }
     }
 }
 
+impl Connection for DenonConnection {
+    fn clone_box(&self) -> Box<dyn Connection> {
+        Box::new(self.clone())
+    }
+
+    fn get(&mut self, state: State) -> Result<StateValue, Error> {
+        DenonConnection::get(self, state)
+    }
+
+    fn get_fresh(&mut self, state: State, max_age: Duration) -> Result<StateValue, Error> {
+        DenonConnection::get_fresh(self, state, max_age)
+    }
+
+    fn query_timeout(&self) -> Duration {
+        DenonConnection::query_timeout(self)
+    }
+
+    fn refresh_all(&mut self) -> Result<Vec<StateValue>, Error> {
+        DenonConnection::refresh_all(self)
+    }
+
+    fn set_max_age(&mut self, max_age: Option<Duration>) {
+        DenonConnection::set_max_age(self, max_age)
+    }
+
+    fn set(&mut self, sstate: SetState) -> Result<(), io::Error> {
+        DenonConnection::set(self, sstate)
+    }
+
+    fn set_confirmed(&mut self, sstate: SetState, timeout: Duration) -> Result<StateValue, Error> {
+        DenonConnection::set_confirmed(self, sstate, timeout)
+    }
+
+    fn query_many(&mut self, states: &[State]) -> Result<Vec<StateValue>, Error> {
+        DenonConnection::query_many(self, states, self.query_timeout)
+    }
+
+    fn received(&self, state: State) -> Option<Received> {
+        DenonConnection::received(self, state)
+    }
+
+    fn send_raw(&mut self, command: &str, wait: Duration) -> Result<Vec<String>, io::Error> {
+        DenonConnection::send_raw(self, command, wait)
+    }
+
+    fn model(&self) -> &'static Model {
+        DenonConnection::model(self)
+    }
+
+    fn parse_errors(&self) -> usize {
+        DenonConnection::parse_errors(self)
+    }
+
+    fn subscribe(&self, states: Option<&[State]>) -> Result<Receiver<Change>, io::Error> {
+        Ok(match states {
+            Some(states) => self.subscribe_to(states),
+            None => DenonConnection::subscribe(self),
+        })
+    }
+
+    fn events(&self) -> Result<Receiver<ConnectionEvent>, io::Error> {
+        Ok(DenonConnection::events(self))
+    }
+
+    fn health(&self) -> Health {
+        DenonConnection::health(self)
+    }
+
+    fn stop(&mut self) -> Result<(), io::Error> {
+        DenonConnection::stop(self)
+    }
+}
+
 #[cfg(test)]
 pub mod test {
     use mockall::Sequence;
     use predicates::ord::eq;
 
-    use super::{thread_func_impl, DenonConnection};
-    use crate::denon_connection::{read, write_string};
+    use super::{
+        parse_response, shutdown_error, thread_func_impl, Backoff, ConnectionEvent,
+        DenonConnection, Health, Origin, Pacing, Received, Shared,
+    };
+    use crate::codec::LineCodec;
+    use crate::denon_connection::{decode_line, write_string};
+    use crate::error;
     use crate::logger::{nothing, MockLogger};
+    use crate::model::{default_model, find_model};
     use crate::state::{PowerState, SetState, SourceInputState, State, StateValue};
-    use crate::stream::{create_tcp_stream, MockReadStream, MockShutdownStream};
+    use crate::stream::{
+        create_tcp_stream, tcp_stream_factory, MockReadStream, MockShutdownStream, ReadStream,
+    };
     use crate::StdoutLogger;
-    use std::cmp::min;
     use std::io::{self, Error};
     use std::net::{TcpListener, TcpStream};
-    use std::rc::Rc;
+    use std::sync::mpsc::RecvError;
     use std::sync::Arc;
-    use std::thread::yield_now;
+    use std::thread::{self, yield_now};
+    use std::time::{Duration, Instant};
 
     pub fn create_connected_connection() -> Result<(TcpStream, DenonConnection), io::Error> {
         let listen_socket = TcpListener::bind("localhost:0")?;
         let addr = listen_socket.local_addr()?;
         let s = create_tcp_stream(addr.ip().to_string().as_str(), addr.port())?;
-        let dc = DenonConnection::new(s, Rc::new(StdoutLogger::default()))?;
+        let dc = DenonConnection::new(s, Arc::new(StdoutLogger::default()), default_model())?;
         let (to_denon_client, _) = listen_socket.accept()?;
         Ok((to_denon_client, dc))
     }
 
-    fn copy_string_into_slice(src: &str, dst: &mut [u8]) -> usize {
-        let length = min(src.len(), dst.len());
-        dst[0..length].copy_from_slice(&src.as_bytes()[0..length]);
-        length
+    fn create_reconnecting_connection(
+        listen_socket: &TcpListener,
+        backoff: Backoff,
+    ) -> Result<DenonConnection, io::Error> {
+        let addr = listen_socket.local_addr()?;
+        DenonConnection::reconnecting(
+            tcp_stream_factory(addr.ip().to_string().as_str(), addr.port()),
+            backoff,
+            Arc::new(StdoutLogger::default()),
+            default_model(),
+            Pacing::default(),
+        )
+    }
+
+    fn test_shared() -> Shared {
+        Shared::new(
+            Box::new(MockShutdownStream::new()),
+            default_model(),
+            Pacing::default(),
+        )
     }
 
     macro_rules! wait_for_value_in_database {
         ($denon_connection:ident, $sstate:expr) => {
             let (state, value) = $sstate.convert();
             for _ in 0..100000 {
-                if $denon_connection.get(state)? == value {
+                if $denon_connection.get(state).ok() == Some(value) {
                     break;
                 }
                 yield_now();
@@ -227,56 +975,394 @@ pub mod test {
     macro_rules! assert_db_value {
         ($denon_connection:ident, $sstate:expr) => {
             let (state, value) = $sstate.convert();
-            assert_eq!($denon_connection.get(state)?, value);
+            assert_eq!($denon_connection.get(state).ok(), Some(value));
         };
     }
 
     #[test]
-    fn connection_gets_no_reply_and_returns_unknown() -> Result<(), io::Error> {
-        let (mut to_denon_client, mut dc) = create_connected_connection()?;
-        let rc = dc.get(State::MainVolume)?;
-        let query = read(&mut to_denon_client, 1)?;
-        assert_eq!(rc, StateValue::Unknown);
+    fn connection_gets_no_reply_and_times_out() -> Result<(), io::Error> {
+        let (to_denon_client, mut dc) = create_connected_connection()?;
+        let rc = dc.get_timeout(State::MainVolume, Duration::from_millis(100));
+        let query = read_lines(&to_denon_client, 1)?;
+        assert!(matches!(rc, Err(error::Error::Timeout(State::MainVolume))));
         assert_eq!(query, vec!["MV?"]);
         Ok(())
     }
 
     #[test]
-    fn connection_sends_main_volume_to_receiver() -> Result<(), io::Error> {
+    fn get_returns_as_soon_as_answer_arrives() -> Result<(), error::Error> {
+        let (mut to_denon_client, mut dc) = create_connected_connection()?;
+        let responder = thread::spawn(move || -> Result<Vec<String>, io::Error> {
+            let received = read_lines(&to_denon_client, 1)?;
+            write_string(&mut to_denon_client, "PWON\r")?;
+            Ok(received)
+        });
+        // waiting for the deadline instead of the answer would hang the test
+        assert_eq!(
+            StateValue::Power(PowerState::On),
+            dc.get_timeout(State::Power, Duration::from_secs(3600))?
+        );
+        assert_eq!(vec!["PW?"], responder.join().unwrap()?);
+        assert_eq!(
+            Some(Origin::Reply),
+            dc.received(State::Power).map(|received| received.origin)
+        );
+        Ok(())
+    }
+
+    fn wait_for_received(dc: &DenonConnection, state: State) -> Received {
+        loop {
+            if let Some(received) = dc.received(state) {
+                return received;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn received_values_record_origin() -> Result<(), error::Error> {
+        let (mut to_denon_client, mut dc) = create_connected_connection()?;
+        write_string(&mut to_denon_client, "MV230\r")?;
+        let event = wait_for_received(&dc, State::MainVolume);
+        assert_eq!(StateValue::Integer(230), event.value);
+        assert_eq!(Origin::Event, event.origin);
+
+        let responder = thread::spawn(move || -> Result<Vec<String>, io::Error> {
+            let received = read_lines(&to_denon_client, 1)?;
+            write_string(&mut to_denon_client, "PWON\r")?;
+            Ok(received)
+        });
+        dc.get(State::Power)?;
+        let reply = dc.received(State::Power).unwrap();
+        assert_eq!(Origin::Reply, reply.origin);
+        assert!(reply.updated > event.updated);
+        assert_eq!(vec!["PW?"], responder.join().unwrap()?);
+        Ok(())
+    }
+
+    #[test]
+    fn get_fresh_queries_outdated_value() -> Result<(), error::Error> {
+        let (mut to_denon_client, mut dc) = create_connected_connection()?;
+        write_string(&mut to_denon_client, "PWON\r")?;
+        wait_for_received(&dc, State::Power);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(
+            StateValue::Power(PowerState::On),
+            dc.get_fresh(State::Power, Duration::from_secs(10))?
+        );
+
+        let responder = thread::spawn(move || -> Result<Vec<String>, io::Error> {
+            let received = read_lines(&to_denon_client, 1)?;
+            write_string(&mut to_denon_client, "PWSTANDBY\r")?;
+            Ok(received)
+        });
+        assert_eq!(
+            StateValue::Power(PowerState::Standby),
+            dc.get_fresh(State::Power, Duration::from_millis(10))?
+        );
+        assert_eq!(vec!["PW?"], responder.join().unwrap()?);
+        Ok(())
+    }
+
+    #[test]
+    fn get_queries_values_older_than_max_age() -> Result<(), error::Error> {
+        let (mut to_denon_client, mut dc) = create_connected_connection()?;
+        write_string(&mut to_denon_client, "MV230\r")?;
+        wait_for_received(&dc, State::MainVolume);
+        dc.set_max_age(Some(Duration::ZERO));
+
+        let responder = thread::spawn(move || -> Result<Vec<String>, io::Error> {
+            let received = read_lines(&to_denon_client, 1)?;
+            write_string(&mut to_denon_client, "MV240\r")?;
+            Ok(received)
+        });
+        assert_eq!(StateValue::Integer(240), dc.get(State::MainVolume)?);
+        assert_eq!(vec!["MV?"], responder.join().unwrap()?);
+        Ok(())
+    }
+
+    #[test]
+    fn lost_connection_is_reported() -> Result<(), error::Error> {
+        let (mut to_denon_client, mut dc) = create_connected_connection()?;
+        write_string(&mut to_denon_client, "PWON\r")?;
+        wait_for_received(&dc, State::Power);
+        assert_eq!(Health::Connected, dc.health());
+        drop(to_denon_client);
+        while !matches!(dc.health(), Health::Lost(_)) {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(
+            Health::Lost(String::from("closed by receiver")),
+            dc.health()
+        );
+        let error = dc.get(State::Power).unwrap_err();
+        assert!(matches!(&error, error::Error::IO(e) if e.kind() == io::ErrorKind::NotConnected));
+        assert!(format!("{:?}", error).contains("connection lost: closed by receiver"));
+        let error = dc.set(SetState::MainVolume(230)).unwrap_err();
+        assert_eq!("connection lost: closed by receiver", error.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn subscribers_receive_changes() -> Result<(), error::Error> {
+        let (mut to_denon_client, dc) = create_connected_connection()?;
+        let all = dc.subscribe();
+        let volumes = dc.subscribe_to(&[State::MainVolume]);
+        write_string(&mut to_denon_client, "MV230\rMV230\rPWON\rMV240\r")?;
+
+        let timeout = Duration::from_secs(5);
+        let change = volumes.recv_timeout(timeout).unwrap();
+        assert_eq!(
+            (State::MainVolume, None, StateValue::Integer(230)),
+            (change.state, change.old, change.new)
+        );
+        let change = volumes.recv_timeout(timeout).unwrap();
+        assert_eq!(
+            (Some(StateValue::Integer(230)), StateValue::Integer(240)),
+            (change.old, change.new)
+        );
+        assert!(change.time <= Instant::now());
+
+        let states: Vec<State> = all.iter().take(3).map(|change| change.state).collect();
+        assert_eq!(
+            vec![State::MainVolume, State::Power, State::MainVolume],
+            states
+        );
+        assert!(all.try_recv().is_err());
+        Ok(())
+    }
+
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    #[test]
+    fn clones_share_connection_between_threads() -> Result<(), error::Error> {
+        let (mut to_denon_client, dc) = create_connected_connection()?;
+        assert_send_sync(&dc);
+        write_string(&mut to_denon_client, "PWON\rMV230\r")?;
+        wait_for_received(&dc, State::MainVolume);
+
+        let getters: Vec<_> = (0..4)
+            .map(|_| {
+                let mut dc = dc.clone();
+                thread::spawn(move || dc.get(State::MainVolume).ok())
+            })
+            .collect();
+        for getter in getters {
+            assert_eq!(Some(StateValue::Integer(230)), getter.join().unwrap());
+        }
+        let mut clone = dc.clone();
+        drop(dc);
+        clone.set(SetState::MainVolume(240))?;
+        assert_eq!(vec!["MV24"], read_lines(&to_denon_client, 1)?);
+        Ok(())
+    }
+
+    // reads byte by byte, so lines after the last requested one stay in the stream
+    pub fn read_lines(mut stream: &TcpStream, count: usize) -> Result<Vec<String>, io::Error> {
+        let mut codec = LineCodec::default();
+        let mut lines = Vec::new();
+        let mut byte = [0];
+        while lines.len() < count {
+            io::Read::read_exact(&mut stream, &mut byte)?;
+            codec.extend(&byte);
+            lines.extend(codec.next_line().map(|line| decode_line(&line)));
+        }
+        Ok(lines)
+    }
+
+    #[test]
+    fn query_many_sends_queries_in_one_burst() -> Result<(), error::Error> {
+        let (mut to_denon_client, mut dc) = create_connected_connection()?;
+        let responder = thread::spawn(move || -> Result<Vec<String>, io::Error> {
+            // no answer is sent before all queries were received
+            let received = read_lines(&to_denon_client, 3)?;
+            write_string(&mut to_denon_client, "MV230\rSICD\rPWON\r")?;
+            Ok(received)
+        });
+        // queries answered one by one would time out
+        let states = [State::Power, State::SourceInput, State::MainVolume];
+        assert_eq!(
+            vec![
+                StateValue::Power(PowerState::On),
+                StateValue::SourceInput(SourceInputState::Cd),
+                StateValue::Integer(230)
+            ],
+            dc.query_many(&states, Duration::from_secs(5))?
+        );
+        assert_eq!(vec!["PW?", "SI?", "MV?"], responder.join().unwrap()?);
+        Ok(())
+    }
+
+    #[test]
+    fn refresh_all_queries_all_states_again() -> Result<(), error::Error> {
         let (mut to_denon_client, mut dc) = create_connected_connection()?;
+        let responder = thread::spawn(move || -> Result<Vec<String>, io::Error> {
+            let mut received = read_lines(&to_denon_client, 4)?;
+            write_string(&mut to_denon_client, "PWON\rSICD\rMV230\rMVMAX666\r")?;
+            received.append(&mut read_lines(&to_denon_client, 4)?);
+            write_string(&mut to_denon_client, "PWSTANDBY\rSIDVD\rMV200\rMVMAX600\r")?;
+            Ok(received)
+        });
+        assert_eq!(
+            vec![
+                StateValue::Power(PowerState::On),
+                StateValue::SourceInput(SourceInputState::Cd),
+                StateValue::Integer(230),
+                StateValue::Integer(666)
+            ],
+            dc.refresh_all()?
+        );
+        assert_eq!(
+            vec![
+                StateValue::Power(PowerState::Standby),
+                StateValue::SourceInput(SourceInputState::Dvd),
+                StateValue::Integer(200),
+                StateValue::Integer(600)
+            ],
+            dc.refresh_all()?
+        );
+        assert_eq!(
+            ["PW?", "SI?", "MV?", "MVMAX?"].repeat(2),
+            responder.join().unwrap()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn get_fails_when_connection_is_lost() -> Result<(), io::Error> {
+        let (to_denon_client, mut dc) = create_connected_connection()?;
+        drop(to_denon_client);
+        let result = dc.get_timeout(State::Power, Duration::from_secs(10));
+        assert!(matches!(result, Err(error::Error::IO(_))), "{:?}", result);
+        Ok(())
+    }
+
+    #[test]
+    fn connection_sends_main_volume_to_receiver() -> Result<(), io::Error> {
+        let (to_denon_client, mut dc) = create_connected_connection()?;
         dc.set(SetState::MainVolume(666))?;
-        let received = read(&mut to_denon_client, 1)?;
+        let received = read_lines(&to_denon_client, 1)?;
         assert_eq!("MV666", received[0]);
         Ok(())
     }
 
     #[test]
     fn connection_sends_max_volume_to_receiver() -> Result<(), io::Error> {
-        let (mut to_denon_client, mut dc) = create_connected_connection()?;
+        let (to_denon_client, mut dc) = create_connected_connection()?;
         dc.set(SetState::MaxVolume(666))?;
-        let received = read(&mut to_denon_client, 1)?;
+        let received = read_lines(&to_denon_client, 1)?;
         assert_eq!("MVMAX666", received[0]);
         Ok(())
     }
 
     #[test]
     fn connection_sends_source_input_to_receiver() -> Result<(), io::Error> {
-        let (mut to_denon_client, mut dc) = create_connected_connection()?;
+        let (to_denon_client, mut dc) = create_connected_connection()?;
         dc.set(SetState::SourceInput(SourceInputState::Fvp))?;
-        let received = read(&mut to_denon_client, 1)?;
+        let received = read_lines(&to_denon_client, 1)?;
         assert_eq!("SIFVP", received[0]);
         Ok(())
     }
 
+    #[test]
+    fn connection_uses_encoding_of_model() -> Result<(), io::Error> {
+        let listen_socket = TcpListener::bind("localhost:0")?;
+        let addr = listen_socket.local_addr()?;
+        let s = create_tcp_stream(addr.ip().to_string().as_str(), addr.port())?;
+        let model = find_model("AVR-X").unwrap();
+        let mut dc = DenonConnection::new(s, Arc::new(StdoutLogger::default()), model)?;
+        let (mut to_denon_client, _) = listen_socket.accept()?;
+        assert_eq!(model, dc.model());
+
+        dc.set(SetState::SourceInput(SourceInputState::TvAudio))?;
+        let received = read_lines(&to_denon_client, 1)?;
+        assert_eq!("SITV", received[0]);
+
+        write_string(&mut to_denon_client, "SITV\r")?;
+        wait_for_value_in_database!(dc, SetState::SourceInput(SourceInputState::TvAudio));
+        assert_db_value!(dc, SetState::SourceInput(SourceInputState::TvAudio));
+        Ok(())
+    }
+
     #[test]
     fn connection_sends_power_to_receiver() -> Result<(), io::Error> {
-        let (mut to_denon_client, mut dc) = create_connected_connection()?;
+        let (to_denon_client, mut dc) = create_connected_connection()?;
         dc.set(SetState::Power(PowerState::On))?;
-        let received = read(&mut to_denon_client, 1)?;
+        let received = read_lines(&to_denon_client, 1)?;
         assert_eq!("PWON", received[0]);
         Ok(())
     }
 
+    #[test]
+    fn set_confirmed_returns_echoed_state() -> Result<(), error::Error> {
+        let (mut to_denon_client, mut dc) = create_connected_connection()?;
+        let responder = thread::spawn(move || -> Result<Vec<String>, io::Error> {
+            let received = read_lines(&to_denon_client, 1)?;
+            write_string(&mut to_denon_client, "SIDVD\r")?;
+            Ok(received)
+        });
+        let sstate = SetState::SourceInput(SourceInputState::Dvd);
+        assert_eq!(
+            StateValue::SourceInput(SourceInputState::Dvd),
+            dc.set_confirmed(sstate, Duration::from_secs(5))?
+        );
+        assert_eq!(vec!["SIDVD"], responder.join().unwrap()?);
+        Ok(())
+    }
+
+    #[test]
+    fn set_confirmed_waits_for_echo_after_command_was_held_back() -> Result<(), error::Error> {
+        let (mut to_denon_client, mut dc) = create_connected_connection()?;
+        let responder = thread::spawn(move || -> Result<Vec<String>, io::Error> {
+            let mut received = read_lines(&to_denon_client, 1)?;
+            received.append(&mut read_lines(&to_denon_client, 1)?);
+            write_string(&mut to_denon_client, "SIDVD\r")?;
+            Ok(received)
+        });
+        // the source input is only sent after the power on delay
+        dc.set(SetState::Power(PowerState::On))?;
+        let sstate = SetState::SourceInput(SourceInputState::Dvd);
+        assert_eq!(
+            StateValue::SourceInput(SourceInputState::Dvd),
+            dc.set_confirmed(sstate, Duration::from_millis(200))?
+        );
+        assert_eq!(vec!["PWON", "SIDVD"], responder.join().unwrap()?);
+        Ok(())
+    }
+
+    #[test]
+    fn set_confirmed_fails_on_other_state() -> Result<(), error::Error> {
+        let (mut to_denon_client, mut dc) = create_connected_connection()?;
+        let responder = thread::spawn(move || -> Result<(), io::Error> {
+            read_lines(&to_denon_client, 1)?;
+            write_string(&mut to_denon_client, "MV50\r")
+        });
+        let result = dc.set_confirmed(SetState::MainVolume(600), Duration::from_secs(5));
+        responder.join().unwrap()?;
+        assert!(matches!(
+            result,
+            Err(error::Error::Rejected(
+                SetState::MainVolume(600),
+                StateValue::Integer(500)
+            ))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn set_confirmed_times_out_without_echo() -> Result<(), error::Error> {
+        let (_to_denon_client, mut dc) = create_connected_connection()?;
+        let sstate = SetState::Power(PowerState::Standby);
+        assert!(matches!(
+            dc.set_confirmed(sstate, Duration::from_millis(50)),
+            Err(error::Error::Unconfirmed(SetState::Power(
+                PowerState::Standby
+            )))
+        ));
+        Ok(())
+    }
+
     #[test]
     fn connection_receives_volume_from_receiver() -> Result<(), io::Error> {
         let (mut to_denon_client, mut dc) = create_connected_connection()?;
@@ -288,9 +1374,13 @@ pub mod test {
     #[test]
     fn connection_receives_multiple_values_volume_from_receiver() -> Result<(), io::Error> {
         let (mut to_denon_client, mut dc) = create_connected_connection()?;
-        assert_eq!(StateValue::Unknown, dc.get(State::MainVolume)?);
-        assert_eq!(StateValue::Unknown, dc.get(State::SourceInput)?);
-        assert_eq!(StateValue::Unknown, dc.get(State::Power)?);
+        let timeout = Duration::from_millis(10);
+        for state in [State::MainVolume, State::SourceInput, State::Power] {
+            assert!(matches!(
+                dc.get_timeout(state, timeout),
+                Err(error::Error::Timeout(s)) if s == state
+            ));
+        }
         write_string(&mut to_denon_client, "MV234\rSICD\rPWON\r")?;
         assert_db_value!(dc, SetState::MainVolume(234));
         assert_db_value!(dc, SetState::SourceInput(SourceInputState::Cd));
@@ -310,51 +1400,263 @@ pub mod test {
         Ok(())
     }
 
+    #[test]
+    fn connection_skips_and_counts_malformed_lines() -> Result<(), io::Error> {
+        let (mut to_denon_client, mut dc) = create_connected_connection()?;
+        write_string(&mut to_denon_client, "MV\rMVMAXabc\rMV234\r")?;
+        wait_for_value_in_database!(dc, SetState::MainVolume(234));
+        assert_db_value!(dc, SetState::MainVolume(234));
+        assert_eq!(2, dc.parse_errors());
+        Ok(())
+    }
+
+    #[test]
+    fn send_raw_returns_received_lines() -> Result<(), io::Error> {
+        let (mut to_denon_client, mut dc) = create_connected_connection()?;
+        // the stream is kept open, a closed connection has no valid values
+        let responder = thread::spawn(move || -> Result<(Vec<String>, TcpStream), io::Error> {
+            let received = read_lines(&to_denon_client, 1)?;
+            write_string(&mut to_denon_client, "PSBAS 50\rMV234\r")?;
+            Ok((received, to_denon_client))
+        });
+        let lines = dc.send_raw("PSBAS ?", Duration::from_millis(200))?;
+        let (received, _to_denon_client) = responder.join().unwrap()?;
+        assert_eq!(vec!["PSBAS ?"], received);
+        assert_eq!(vec!["PSBAS 50", "MV234"], lines);
+        assert_db_value!(dc, SetState::MainVolume(234));
+        Ok(())
+    }
+
+    #[test]
+    fn send_raw_without_reply_returns_empty_vec() -> Result<(), io::Error> {
+        let (_to_denon_client, mut dc) = create_connected_connection()?;
+        let lines = dc.send_raw("PS?", Duration::from_millis(10))?;
+        assert_eq!(Vec::<String>::new(), lines);
+        Ok(())
+    }
+
+    #[test]
+    fn connection_reconnects_and_queries_again() -> Result<(), io::Error> {
+        let listen_socket = TcpListener::bind("localhost:0")?;
+        let backoff = Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(40),
+        };
+        let mut dc = create_reconnecting_connection(&listen_socket, backoff)?;
+        let events = dc.events();
+        let (to_denon_client, _) = listen_socket.accept()?;
+        assert!(dc
+            .get_timeout(State::MainVolume, Duration::from_millis(10))
+            .is_err());
+        assert_eq!(vec!["MV?"], read_lines(&to_denon_client, 1)?);
+
+        drop(to_denon_client);
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            Ok(ConnectionEvent::Disconnected),
+            events.recv_timeout(timeout)
+        );
+        let (mut to_denon_client, _) = listen_socket.accept()?;
+        assert_eq!(
+            Ok(ConnectionEvent::Reconnected),
+            events.recv_timeout(timeout)
+        );
+        assert_eq!(vec!["MV?"], read_lines(&to_denon_client, 1)?);
+
+        write_string(&mut to_denon_client, "MV320\r")?;
+        wait_for_value_in_database!(dc, SetState::MainVolume(320));
+        assert_db_value!(dc, SetState::MainVolume(320));
+        Ok(())
+    }
+
+    #[test]
+    fn drop_interrupts_reconnecting() -> Result<(), io::Error> {
+        let listen_socket = TcpListener::bind("localhost:0")?;
+        let backoff = Backoff {
+            initial: Duration::from_secs(60),
+            max: Duration::from_secs(60),
+        };
+        let dc = create_reconnecting_connection(&listen_socket, backoff)?;
+        let events = dc.events();
+        let (to_denon_client, _) = listen_socket.accept()?;
+        drop(to_denon_client);
+        assert_eq!(
+            Ok(ConnectionEvent::Disconnected),
+            events.recv_timeout(Duration::from_secs(5))
+        );
+
+        // without being interrupted, drop would wait out the backoff and
+        // the reconnecting thread would connect again before ending
+        drop(dc);
+        assert_eq!(Err(RecvError), events.recv());
+        listen_socket.set_nonblocking(true)?;
+        assert_eq!(
+            io::ErrorKind::WouldBlock,
+            listen_socket.accept().unwrap_err().kind()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn backoff_doubles_delay_up_to_max() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(300),
+        };
+        assert_eq!(Duration::from_millis(200), backoff.next(backoff.initial));
+        assert_eq!(
+            Duration::from_millis(300),
+            backoff.next(Duration::from_millis(200))
+        );
+        assert_eq!(Duration::from_millis(300), backoff.next(backoff.max));
+    }
+
+    pub fn fill_lines(
+        codec: &mut LineCodec,
+        stream: &dyn ReadStream,
+        count: usize,
+    ) -> Result<Vec<String>, io::Error> {
+        let mut lines = Vec::new();
+        while lines.len() < count {
+            codec.fill(stream)?;
+            lines.extend(std::iter::from_fn(|| codec.next_line()).map(|line| decode_line(&line)));
+        }
+        Ok(lines)
+    }
+
     #[test]
     fn read_without_valid_content_returns_empty_vec() -> Result<(), io::Error> {
         let listen_socket = TcpListener::bind("localhost:0")?;
         let addr = listen_socket.local_addr()?;
-        let mut client = TcpStream::connect(addr)?;
+        let client = TcpStream::connect(addr)?;
         let (mut to_client, _) = listen_socket.accept()?;
 
-        // as \r is missing, read() does not read or extract anything
+        // as \r is missing, no line is extracted
+        let mut codec = LineCodec::default();
         write_string(&mut to_client, "blub")?;
-        let lines = read(&mut client, 1)?;
-        assert_eq!(lines, Vec::<String>::new());
+        codec.fill(&client)?;
+        assert_eq!(None, codec.next_line());
 
-        // read() reads until \r and leaves other data in the stream
+        // the line ends at \r, other data is kept for the next line
         write_string(&mut to_client, "bla\rfoo")?;
-        let lines = read(&mut client, 2)?;
-        assert_eq!(lines, vec!["blubbla".to_owned()]);
+        assert_eq!(vec!["blubbla"], fill_lines(&mut codec, &client, 1)?);
+        assert_eq!(None, codec.next_line());
+
+        Ok(())
+    }
 
+    #[test]
+    fn read_reads_lines_longer_than_one_peek() -> Result<(), io::Error> {
+        let listen_socket = TcpListener::bind("localhost:0")?;
+        let addr = listen_socket.local_addr()?;
+        let client = TcpStream::connect(addr)?;
+        let (mut to_client, _) = listen_socket.accept()?;
+
+        let long_line = format!("NSE1{}", "x".repeat(1000));
+        write_string(&mut to_client, &format!("{}\rPWON\r", long_line))?;
+        let lines = fill_lines(&mut LineCodec::default(), &client, 2)?;
+        assert_eq!(vec![long_line, String::from("PWON")], lines);
         Ok(())
     }
 
     #[test]
-    fn read_reads_content_gets_error_and_returns_content() -> Result<(), io::Error> {
+    fn read_decodes_latin1() -> Result<(), io::Error> {
+        let listen_socket = TcpListener::bind("localhost:0")?;
+        let addr = listen_socket.local_addr()?;
+        let mut client = TcpStream::connect(addr)?;
+        let (to_client, _) = listen_socket.accept()?;
+
+        io::Write::write_all(&mut client, b"NSE1\x02Mot\xf6rhead\rNSE2Caf\xc3\xa9\r")?;
+        let lines = fill_lines(&mut LineCodec::default(), &to_client, 2)?;
+        assert_eq!(vec!["NSE1\u{2}Motörhead", "NSE2Café"], lines);
+        Ok(())
+    }
+
+    #[test]
+    fn connection_keeps_up_with_bursts() -> Result<(), io::Error> {
+        let (mut to_denon_client, dc) = create_connected_connection()?;
+        let mut burst = String::new();
+        for i in 0..20_000 {
+            burst += &format!("MV{}\rNSE1{}\r", i % 98, "x".repeat(i % 200));
+        }
+        burst += "MV99\r";
+        let volumes = dc.subscribe_to(&[State::MainVolume]);
+        write_string(&mut to_denon_client, &burst)?;
+        // every volume differs from the one before, so none may be lost
+        let changes: Vec<_> = volumes
+            .iter()
+            .take_while(|change| change.new != StateValue::Integer(990))
+            .collect();
+        assert_eq!(20_000, changes.len());
+        assert_eq!(StateValue::Integer(19_999 % 98 * 10), changes[19_999].new);
+        assert_eq!(0, dc.parse_errors());
+        Ok(())
+    }
+
+    #[test]
+    fn connection_resynchronises_after_garbage() {
+        let mut logger = MockLogger::new();
+        logger
+            .expect_log()
+            .once()
+            .with(eq("skipping line: too long without line end"))
+            .returning(nothing);
+        let shared = test_shared();
         let mut sequence = Sequence::new();
         let mut mstream = MockReadStream::new();
-        // peek works
+        for chunk in (0..17)
+            .map(|_| vec![b'x'; 4096])
+            .chain([b"garbage\rMV50\r".to_vec()])
+        {
+            let peeked = chunk.clone();
+            mstream
+                .expect_peekly()
+                .once()
+                .in_sequence(&mut sequence)
+                .returning(move |buf| {
+                    buf[..peeked.len()].copy_from_slice(&peeked);
+                    Ok(peeked.len())
+                });
+            mstream
+                .expect_read_exactly()
+                .once()
+                .in_sequence(&mut sequence)
+                .returning(|_| Ok(()));
+        }
         mstream
             .expect_peekly()
             .once()
             .in_sequence(&mut sequence)
-            .returning(|buf| Ok(copy_string_into_slice("some_data\r", buf)));
-        // read works
-        mstream
-            .expect_read_exactly()
-            .once()
-            .in_sequence(&mut sequence)
-            .returning(|_| Ok(()));
-        // peek with error
-        mstream
-            .expect_peekly()
+            .returning(|_| Ok(0));
+
+        assert!(thread_func_impl(&mstream, &shared, &logger).is_ok());
+        assert_eq!(
+            Some(StateValue::Integer(500)),
+            shared
+                .state
+                .lock()
+                .unwrap()
+                .get(&State::MainVolume)
+                .map(|received| received.value)
+        );
+        assert_eq!(1, shared.parse_errors.into_inner());
+    }
+
+    #[test]
+    fn parse_response_logs_and_skips_malformed_lines() {
+        let mut logger = MockLogger::new();
+        logger
+            .expect_log()
             .once()
-            .in_sequence(&mut sequence)
-            .returning(|_| Err(Error::from(io::ErrorKind::ConnectionAborted)));
-        let lines = read(&mut mstream, 2)?;
-        assert_eq!(vec!(String::from("some_data")), lines);
-        Ok(())
+            .with(eq("skipping line: invalid value '' in line 'MV', expected a volume of two or three digits"))
+            .returning(nothing);
+        let shared = test_shared();
+        let response = vec![String::from("MV"), String::from("PWON")];
+        assert_eq!(
+            vec![SetState::Power(PowerState::On)],
+            parse_response(&response, shared.model, &shared.parse_errors, &logger)
+        );
+        assert_eq!(1, shared.parse_errors.into_inner());
     }
 
     #[test]
@@ -363,8 +1665,8 @@ pub mod test {
         mstream
             .expect_peekly()
             .returning(|_| Err(Error::from(io::ErrorKind::ConnectionAborted)));
-        let state = Arc::default();
-        let thread_err = thread_func_impl(&mstream, state);
+        let logger = MockLogger::new();
+        let thread_err = thread_func_impl(&mstream, &test_shared(), &logger);
         assert!(thread_err.is_err());
         assert_eq!(
             io::ErrorKind::ConnectionAborted,
@@ -372,6 +1674,17 @@ pub mod test {
         );
     }
 
+    #[test]
+    fn thread_func_impl_returns_ok_after_shutdown() {
+        let mut mstream = MockReadStream::new();
+        mstream
+            .expect_peekly()
+            .once()
+            .returning(|_| Err(shutdown_error()));
+        let logger = MockLogger::new();
+        assert!(thread_func_impl(&mstream, &test_shared(), &logger).is_ok());
+    }
+
     #[test]
     fn thread_func_impl_gets_timeout_then_error_and_returns() {
         let mut sequence = Sequence::new();
@@ -386,8 +1699,8 @@ pub mod test {
             .once()
             .in_sequence(&mut sequence)
             .returning(|_| Err(Error::from(io::ErrorKind::ConnectionAborted)));
-        let state = Arc::default();
-        let thread_err = thread_func_impl(&mstream, state);
+        let logger = MockLogger::new();
+        let thread_err = thread_func_impl(&mstream, &test_shared(), &logger);
         assert!(thread_err.is_err());
         assert_eq!(
             io::ErrorKind::ConnectionAborted,
@@ -420,7 +1733,7 @@ pub mod test {
             .with(eq(format!("got error: {}", ERROR_MESSAGE)))
             .returning(nothing);
 
-        let dc = DenonConnection::new(Box::new(msdstream), Rc::new(logger));
+        let dc = DenonConnection::new(Box::new(msdstream), Arc::new(logger), default_model());
         assert!(dc.is_ok());
     }
 }
@@ -1,11 +1,24 @@
 use crate::avahi_error;
+use crate::state::{SetState, State, StateValue};
+use std::fmt::{self, Display, Formatter};
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     ParseInt(std::num::ParseIntError),
-    Avahi(avahi_error::Error),
+    // the receiver could not be found with avahi
+    Avahi(String),
     IO(std::io::Error),
     Input(String),
+    Heos(String),
+    // the receiver did not report the new state in time
+    Unconfirmed(SetState),
+    // the receiver reported another state than the one set
+    Rejected(SetState, StateValue),
+    // the receiver did not answer a query in time
+    Timeout(State),
+    // the receiver reported a value not fitting the state, e.g. an empty one
+    UnexpectedValue(State, StateValue),
 }
 
 impl std::convert::From<std::num::ParseIntError> for Error {
@@ -16,7 +29,7 @@ impl std::convert::From<std::num::ParseIntError> for Error {
 
 impl std::convert::From<avahi_error::Error> for Error {
     fn from(avahi_error: avahi_error::Error) -> Self {
-        Error::Avahi(avahi_error)
+        Error::Avahi(avahi_error.to_string())
     }
 }
 
@@ -32,10 +45,42 @@ impl std::convert::From<String> for Error {
     }
 }
 
+impl Display for Error {
+    fn fmt(&self, format: &mut Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Error::ParseInt(e) => write!(format, "invalid number: {}", e),
+            Error::Avahi(cause) => write!(format, "no receiver found: {}", cause),
+            Error::IO(e) => write!(format, "{}", e),
+            Error::Input(message) | Error::Heos(message) => write!(format, "{}", message),
+            Error::Unconfirmed(sstate) => {
+                write!(format, "receiver did not report {} in time", sstate)
+            }
+            Error::Rejected(sstate, value) => {
+                write!(format, "receiver reported {} instead of {}", value, sstate)
+            }
+            Error::Timeout(state) => write!(format, "receiver did not answer {:?} in time", state),
+            Error::UnexpectedValue(state, value) => {
+                write!(format, "unexpected value {:?} of {:?}", value, state)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::ParseInt(e) => Some(e),
+            Error::IO(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::avahi_error;
     use crate::error::Error;
+    use crate::state::{PowerState, SetState, State};
     use std::io;
 
     macro_rules! check_error {
@@ -56,7 +101,7 @@ mod test {
         check_error!(
             avahi_error::Error::NoHostsFound,
             Error::Avahi(_),
-            "Avahi(NoHostsFound)"
+            "Avahi(\"NoHostsFound\")"
         );
         check_error!(
             std::io::Error::from(io::ErrorKind::AddrInUse),
@@ -65,4 +110,23 @@ mod test {
         );
         check_error!(String::from("blub"), Error::Input(_), "Input(\"blub\")");
     }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            "no receiver found: NoHostsFound",
+            Error::from(avahi_error::Error::NoHostsFound).to_string()
+        );
+        assert_eq!(
+            "receiver did not report PWSTANDBY in time",
+            Error::Unconfirmed(SetState::Power(PowerState::Standby)).to_string()
+        );
+        assert_eq!(
+            "receiver did not answer MainVolume in time",
+            Error::Timeout(State::MainVolume).to_string()
+        );
+        let error: Box<dyn std::error::Error> =
+            Box::new(Error::from(io::Error::from(io::ErrorKind::BrokenPipe)));
+        assert!(error.source().is_some());
+    }
 }
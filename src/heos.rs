@@ -0,0 +1,648 @@
+// HEOS CLI of newer receivers, see the "HEOS CLI Protocol Specification"
+// $ printf "heos://player/get_players\r\n" | nc 0005cd221b08.lan 1255
+// {"heos": {"command": "player/get_players", "result": "success", "message": ""}, "payload": [...]}
+
+use crate::error::Error;
+use crate::stream::{ConnectionStream, ReadStream};
+use serde_json::{json, Value};
+use std::fmt::{Display, Formatter};
+use std::io::{self, ErrorKind, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+pub const HEOS_PORT: u16 = 1255;
+// browsing music services may take several seconds
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Player {
+    pub pid: i64,
+    pub name: String,
+    pub model: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NowPlaying {
+    pub media_type: String,
+    pub song: String,
+    pub artist: String,
+    pub album: String,
+    pub station: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupMember {
+    pub pid: i64,
+    pub name: String,
+    // leader or member
+    pub role: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub gid: i64,
+    pub name: String,
+    pub players: Vec<GroupMember>,
+}
+
+impl Group {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "gid": self.gid,
+            "name": self.name,
+            "players": self.players.iter().map(|p| json!({
+                "pid": p.pid,
+                "name": p.name,
+                "role": p.role,
+            })).collect::<Vec<Value>>(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueItem {
+    pub qid: i64,
+    pub song: String,
+    pub artist: String,
+    pub album: String,
+}
+
+impl QueueItem {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "qid": self.qid,
+            "song": self.song,
+            "artist": self.artist,
+            "album": self.album,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayState {
+    Play,
+    Pause,
+    Stop,
+}
+
+impl Display for PlayState {
+    fn fmt(&self, format: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(format, "{}", format!("{:?}", self).to_lowercase())
+    }
+}
+
+impl FromStr for PlayState {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        [PlayState::Play, PlayState::Pause, PlayState::Stop]
+            .into_iter()
+            .find(|s| s.to_string() == value)
+            .ok_or_else(|| Error::Heos(format!("unknown play state {}", value)))
+    }
+}
+
+/// Reply of the receiver to a command.
+#[derive(Debug)]
+struct Response {
+    message: String,
+    payload: Value,
+}
+
+impl Response {
+    /// Returns the value of `key` in a message like `pid=1&state=play`.
+    fn message_value(&self, key: &str) -> Option<String> {
+        self.message
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| decode_param(v))
+    }
+}
+
+// characters with a meaning in the command line have to be escaped
+fn encode_param(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace('&', "%26")
+        .replace('=', "%3D")
+}
+
+fn decode_param(value: &str) -> String {
+    value
+        .replace("%26", "&")
+        .replace("%3D", "=")
+        .replace("%25", "%")
+}
+
+/// Reads a line terminated by `\n`, which may be longer than one peek.
+fn read_line(stream: &dyn ReadStream) -> Result<String, io::Error> {
+    let mut line = Vec::new();
+    loop {
+        let mut buffer = [0; 1024];
+        let peeked = stream.peekly(&mut buffer)?;
+        if peeked == 0 {
+            return Err(io::Error::from(ErrorKind::UnexpectedEof));
+        }
+        let newline = buffer[..peeked].iter().position(|&c| c == b'\n');
+        let length = newline.map_or(peeked, |pos| pos + 1);
+        stream.read_exactly(&mut buffer[..length])?;
+        line.extend_from_slice(&buffer[..length]);
+        if newline.is_some() {
+            return Ok(String::from_utf8_lossy(&line).trim().to_string());
+        }
+    }
+}
+
+fn integer_of(value: &Value, key: &str) -> i64 {
+    match &value[key] {
+        Value::String(s) => s.parse().unwrap_or_default(),
+        other => other.as_i64().unwrap_or_default(),
+    }
+}
+
+fn string_of(value: &Value, key: &str) -> String {
+    match &value[key] {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+pub struct HeosConnection {
+    to_receiver: Box<dyn ConnectionStream>,
+    from_receiver: Box<dyn ReadStream>,
+    timeout: Duration,
+}
+
+impl HeosConnection {
+    pub fn new(to_receiver: Box<dyn ConnectionStream>) -> Result<HeosConnection, io::Error> {
+        let from_receiver = to_receiver.get_readstream()?;
+        Ok(HeosConnection {
+            to_receiver,
+            from_receiver,
+            timeout: COMMAND_TIMEOUT,
+        })
+    }
+
+    /// How long a command waits for its reply, while events keep arriving.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sends `heos://command?params` and waits for its reply. Events and
+    /// replies to other commands are skipped.
+    fn command(&mut self, command: &str, params: &[(&str, String)]) -> Result<Response, Error> {
+        let mut line = format!("heos://{}", command);
+        for (i, (key, value)) in params.iter().enumerate() {
+            line += if i == 0 { "?" } else { "&" };
+            line += &format!("{}={}", key, encode_param(value));
+        }
+        self.to_receiver
+            .write_all(format!("{}\r\n", line).as_bytes())?;
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!("no reply to {} within {:?}", command, self.timeout),
+                )
+                .into());
+            }
+            let reply = read_line(self.from_receiver.as_ref())?;
+            if reply.is_empty() {
+                continue;
+            }
+            let json: Value = serde_json::from_str(&reply)
+                .map_err(|e| Error::Heos(format!("invalid reply '{}': {}", reply, e)))?;
+            let heos = &json["heos"];
+            let message = string_of(heos, "message");
+            // long running commands are acknowledged before their reply
+            if string_of(heos, "command") != command || message.starts_with("command under process")
+            {
+                continue;
+            }
+            let response = Response {
+                message,
+                payload: json["payload"].clone(),
+            };
+            if string_of(heos, "result") != "success" {
+                return Err(Error::Heos(format!(
+                    "{} failed: {}",
+                    command,
+                    response
+                        .message_value("text")
+                        .unwrap_or(response.message.clone())
+                )));
+            }
+            return Ok(response);
+        }
+    }
+
+    pub fn players(&mut self) -> Result<Vec<Player>, Error> {
+        let response = self.command("player/get_players", &[])?;
+        Ok(response
+            .payload
+            .as_array()
+            .map(|players| {
+                players
+                    .iter()
+                    .map(|p| Player {
+                        pid: integer_of(p, "pid"),
+                        name: string_of(p, "name"),
+                        model: string_of(p, "model"),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    pub fn now_playing(&mut self, pid: i64) -> Result<NowPlaying, Error> {
+        let response = self.command("player/get_now_playing_media", &[("pid", pid.to_string())])?;
+        let media = &response.payload;
+        Ok(NowPlaying {
+            media_type: string_of(media, "type"),
+            song: string_of(media, "song"),
+            artist: string_of(media, "artist"),
+            album: string_of(media, "album"),
+            station: string_of(media, "station"),
+        })
+    }
+
+    pub fn play_state(&mut self, pid: i64) -> Result<PlayState, Error> {
+        let response = self.command("player/get_play_state", &[("pid", pid.to_string())])?;
+        response
+            .message_value("state")
+            .ok_or_else(|| Error::Heos(format!("no play state in '{}'", response.message)))?
+            .parse()
+    }
+
+    pub fn set_play_state(&mut self, pid: i64, state: PlayState) -> Result<(), Error> {
+        self.command(
+            "player/set_play_state",
+            &[("pid", pid.to_string()), ("state", state.to_string())],
+        )
+        .map(|_| ())
+    }
+
+    pub fn play_next(&mut self, pid: i64) -> Result<(), Error> {
+        self.command("player/play_next", &[("pid", pid.to_string())])
+            .map(|_| ())
+    }
+
+    pub fn play_previous(&mut self, pid: i64) -> Result<(), Error> {
+        self.command("player/play_previous", &[("pid", pid.to_string())])
+            .map(|_| ())
+    }
+
+    pub fn volume(&mut self, pid: i64) -> Result<u8, Error> {
+        let response = self.command("player/get_volume", &[("pid", pid.to_string())])?;
+        Ok(response
+            .message_value("level")
+            .ok_or_else(|| Error::Heos(format!("no volume in '{}'", response.message)))?
+            .parse()?)
+    }
+
+    pub fn set_volume(&mut self, pid: i64, level: u8) -> Result<(), Error> {
+        self.command(
+            "player/set_volume",
+            &[
+                ("pid", pid.to_string()),
+                ("level", level.min(100).to_string()),
+            ],
+        )
+        .map(|_| ())
+    }
+
+    pub fn groups(&mut self) -> Result<Vec<Group>, Error> {
+        let response = self.command("group/get_groups", &[])?;
+        let groups = response.payload.as_array().cloned().unwrap_or_default();
+        Ok(groups
+            .iter()
+            .map(|g| Group {
+                gid: integer_of(g, "gid"),
+                name: string_of(g, "name"),
+                players: g["players"]
+                    .as_array()
+                    .map(|players| {
+                        players
+                            .iter()
+                            .map(|p| GroupMember {
+                                pid: integer_of(p, "pid"),
+                                name: string_of(p, "name"),
+                                role: string_of(p, "role"),
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    /// Groups the players with the first one as leader. Only giving the
+    /// leader removes its group. Returns the id of the group, if one exists.
+    pub fn set_group(&mut self, pids: &[i64]) -> Result<Option<i64>, Error> {
+        let pids: Vec<String> = pids.iter().map(|pid| pid.to_string()).collect();
+        let response = self.command("group/set_group", &[("pid", pids.join(","))])?;
+        Ok(response
+            .message_value("gid")
+            .and_then(|gid| gid.parse().ok()))
+    }
+
+    pub fn queue(&mut self, pid: i64) -> Result<Vec<QueueItem>, Error> {
+        let response = self.command("player/get_queue", &[("pid", pid.to_string())])?;
+        let items = response.payload.as_array().cloned().unwrap_or_default();
+        Ok(items
+            .iter()
+            .map(|item| QueueItem {
+                qid: integer_of(item, "qid"),
+                song: string_of(item, "song"),
+                artist: string_of(item, "artist"),
+                album: string_of(item, "album"),
+            })
+            .collect())
+    }
+
+    pub fn play_stream(&mut self, pid: i64, url: &str) -> Result<(), Error> {
+        self.command(
+            "browse/play_stream",
+            &[("pid", pid.to_string()), ("url", url.to_string())],
+        )
+        .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{decode_param, encode_param, HeosConnection, NowPlaying, PlayState, Player};
+    use super::{Group, GroupMember, QueueItem};
+    use crate::error::Error;
+    use crate::stream::create_tcp_stream;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    type FakeServer = JoinHandle<Result<Vec<String>, io::Error>>;
+
+    /// Answers each received command with the next of `replies` and returns
+    /// the received commands.
+    pub fn create_fake_heos_server(
+        replies: Vec<&'static str>,
+    ) -> Result<(FakeServer, u16), io::Error> {
+        let listen_socket = TcpListener::bind("localhost:0")?;
+        let local_port = listen_socket.local_addr()?.port();
+
+        let server = thread::spawn(move || -> Result<Vec<String>, io::Error> {
+            let mut stream = listen_socket.accept()?.0;
+            let mut reader = BufReader::new(stream.try_clone()?);
+            let mut commands = Vec::new();
+            for reply in replies {
+                let mut line = String::new();
+                reader.read_line(&mut line)?;
+                commands.push(line.trim().to_string());
+                stream.write_all(reply.as_bytes())?;
+            }
+            Ok(commands)
+        });
+
+        Ok((server, local_port))
+    }
+
+    pub const PLAYERS: &str = "{\"heos\": {\"command\": \"player/get_players\", \"result\": \"success\", \"message\": \"\"}, \"payload\": [{\"name\": \"Living Room\", \"pid\": 1717, \"model\": \"Denon AVR-X2700H\", \"version\": \"1.0\", \"ip\": \"192.168.1.2\", \"network\": \"wired\", \"lineout\": 0}, {\"name\": \"Kitchen\", \"pid\": -42, \"model\": \"HEOS 1\"}]}\r\n";
+
+    fn connect(replies: Vec<&'static str>) -> Result<(FakeServer, HeosConnection), io::Error> {
+        let (server, port) = create_fake_heos_server(replies)?;
+        let heos = HeosConnection::new(create_tcp_stream("localhost", port)?)?;
+        Ok((server, heos))
+    }
+
+    #[test]
+    fn players_test() -> Result<(), Error> {
+        let (server, mut heos) = connect(vec![PLAYERS])?;
+        assert_eq!(
+            vec![
+                Player {
+                    pid: 1717,
+                    name: String::from("Living Room"),
+                    model: String::from("Denon AVR-X2700H")
+                },
+                Player {
+                    pid: -42,
+                    name: String::from("Kitchen"),
+                    model: String::from("HEOS 1")
+                }
+            ],
+            heos.players()?
+        );
+        assert_eq!(vec!["heos://player/get_players"], server.join().unwrap()?);
+        Ok(())
+    }
+
+    #[test]
+    fn now_playing_test() -> Result<(), Error> {
+        let (server, mut heos) = connect(vec!["{\"heos\": {\"command\": \"player/get_now_playing_media\", \"result\": \"success\", \"message\": \"pid=1717\"}, \"payload\": {\"type\": \"station\", \"song\": \"Song\", \"album\": \"\", \"artist\": \"Artist\", \"station\": \"Radio\", \"sid\": 3}}\r\n"])?;
+        assert_eq!(
+            NowPlaying {
+                media_type: String::from("station"),
+                song: String::from("Song"),
+                artist: String::from("Artist"),
+                album: String::new(),
+                station: String::from("Radio"),
+            },
+            heos.now_playing(1717)?
+        );
+        assert_eq!(
+            vec!["heos://player/get_now_playing_media?pid=1717"],
+            server.join().unwrap()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn play_state_skips_events_and_acknowledgements() -> Result<(), Error> {
+        let (server, mut heos) = connect(vec!["{\"heos\": {\"command\": \"event/player_volume_changed\", \"message\": \"pid=1717&level=20&mute=off\"}}\r\n{\"heos\": {\"command\": \"player/get_play_state\", \"result\": \"success\", \"message\": \"command under process&pid=1717\"}}\r\n{\"heos\": {\"command\": \"player/get_play_state\", \"result\": \"success\", \"message\": \"pid=1717&state=pause\"}}\r\n"])?;
+        assert_eq!(PlayState::Pause, heos.play_state(1717)?);
+        assert_eq!(
+            vec!["heos://player/get_play_state?pid=1717"],
+            server.join().unwrap()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn command_times_out_while_only_events_arrive() -> Result<(), Error> {
+        let listen_socket = TcpListener::bind("localhost:0")?;
+        let port = listen_socket.local_addr()?.port();
+        let server = thread::spawn(move || -> Result<(), io::Error> {
+            let mut stream = listen_socket.accept()?.0;
+            // until the client gives up and closes the connection
+            while stream
+                .write_all(b"{\"heos\": {\"command\": \"event/players_changed\"}}\r\n")
+                .is_ok()
+            {
+                thread::sleep(Duration::from_millis(5));
+            }
+            Ok(())
+        });
+        let mut heos = HeosConnection::new(create_tcp_stream("localhost", port)?)?;
+        heos.set_timeout(Duration::from_millis(50));
+        assert!(matches!(
+            heos.players(),
+            Err(Error::IO(e)) if e.kind() == io::ErrorKind::TimedOut
+        ));
+        drop(heos);
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn controls_player() -> Result<(), Error> {
+        let (server, mut heos) = connect(vec![
+            "{\"heos\": {\"command\": \"player/set_play_state\", \"result\": \"success\", \"message\": \"pid=1&state=play\"}}\r\n",
+            "{\"heos\": {\"command\": \"player/play_next\", \"result\": \"success\", \"message\": \"pid=1\"}}\r\n",
+            "{\"heos\": {\"command\": \"player/play_previous\", \"result\": \"success\", \"message\": \"pid=1\"}}\r\n",
+            "{\"heos\": {\"command\": \"player/set_volume\", \"result\": \"success\", \"message\": \"pid=1&level=100\"}}\r\n",
+            "{\"heos\": {\"command\": \"player/get_volume\", \"result\": \"success\", \"message\": \"pid=1&level=100\"}}\r\n",
+        ])?;
+        heos.set_play_state(1, PlayState::Play)?;
+        heos.play_next(1)?;
+        heos.play_previous(1)?;
+        heos.set_volume(1, 120)?;
+        assert_eq!(100, heos.volume(1)?);
+        assert_eq!(
+            vec![
+                "heos://player/set_play_state?pid=1&state=play",
+                "heos://player/play_next?pid=1",
+                "heos://player/play_previous?pid=1",
+                "heos://player/set_volume?pid=1&level=100",
+                "heos://player/get_volume?pid=1",
+            ],
+            server.join().unwrap()?
+        );
+        Ok(())
+    }
+
+    pub const GROUPS: &str = "{\"heos\": {\"command\": \"group/get_groups\", \"result\": \"success\", \"message\": \"\"}, \"payload\": [{\"name\": \"Living Room + Kitchen\", \"gid\": 1717, \"players\": [{\"name\": \"Living Room\", \"pid\": 1717, \"role\": \"leader\"}, {\"name\": \"Kitchen\", \"pid\": -42, \"role\": \"member\"}]}]}\r\n";
+
+    #[test]
+    fn groups_test() -> Result<(), Error> {
+        let (server, mut heos) = connect(vec![GROUPS])?;
+        let groups = heos.groups()?;
+        assert_eq!(
+            vec![Group {
+                gid: 1717,
+                name: String::from("Living Room + Kitchen"),
+                players: vec![
+                    GroupMember {
+                        pid: 1717,
+                        name: String::from("Living Room"),
+                        role: String::from("leader")
+                    },
+                    GroupMember {
+                        pid: -42,
+                        name: String::from("Kitchen"),
+                        role: String::from("member")
+                    }
+                ]
+            }],
+            groups
+        );
+        assert_eq!(
+            "{\"gid\":1717,\"name\":\"Living Room + Kitchen\",\"players\":[{\"name\":\"Living Room\",\"pid\":1717,\"role\":\"leader\"},{\"name\":\"Kitchen\",\"pid\":-42,\"role\":\"member\"}]}",
+            groups[0].to_json().to_string()
+        );
+        assert_eq!(vec!["heos://group/get_groups"], server.join().unwrap()?);
+        Ok(())
+    }
+
+    #[test]
+    fn set_group_test() -> Result<(), Error> {
+        let (server, mut heos) = connect(vec![
+            "{\"heos\": {\"command\": \"group/set_group\", \"result\": \"success\", \"message\": \"gid=1717&name=Living Room + Kitchen&pid=1717,-42\"}}\r\n",
+            "{\"heos\": {\"command\": \"group/set_group\", \"result\": \"success\", \"message\": \"pid=1717\"}}\r\n",
+        ])?;
+        assert_eq!(Some(1717), heos.set_group(&[1717, -42])?);
+        assert_eq!(None, heos.set_group(&[1717])?);
+        assert_eq!(
+            vec![
+                "heos://group/set_group?pid=1717,-42",
+                "heos://group/set_group?pid=1717"
+            ],
+            server.join().unwrap()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn queue_test() -> Result<(), Error> {
+        let (server, mut heos) = connect(vec!["{\"heos\": {\"command\": \"player/get_queue\", \"result\": \"success\", \"message\": \"pid=1717\"}, \"payload\": [{\"song\": \"Song\", \"album\": \"Album\", \"artist\": \"Artist\", \"image_url\": \"\", \"qid\": 1, \"mid\": \"1\", \"album_id\": \"2\"}]}\r\n"])?;
+        let queue = heos.queue(1717)?;
+        assert_eq!(
+            vec![QueueItem {
+                qid: 1,
+                song: String::from("Song"),
+                artist: String::from("Artist"),
+                album: String::from("Album")
+            }],
+            queue
+        );
+        assert_eq!(
+            "{\"album\":\"Album\",\"artist\":\"Artist\",\"qid\":1,\"song\":\"Song\"}",
+            queue[0].to_json().to_string()
+        );
+        assert_eq!(
+            vec!["heos://player/get_queue?pid=1717"],
+            server.join().unwrap()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn play_stream_escapes_url() -> Result<(), Error> {
+        let (server, mut heos) = connect(vec!["{\"heos\": {\"command\": \"browse/play_stream\", \"result\": \"success\", \"message\": \"pid=1717&url=http://radio.example/stream?a=1%26b=2\"}}\r\n"])?;
+        heos.play_stream(1717, "http://radio.example/stream?a=1&b=2")?;
+        assert_eq!(
+            vec![
+                "heos://browse/play_stream?pid=1717&url=http://radio.example/stream?a%3D1%26b%3D2"
+            ],
+            server.join().unwrap()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn failure_is_returned() -> Result<(), io::Error> {
+        let (server, mut heos) = connect(vec!["{\"heos\": {\"command\": \"player/play_next\", \"result\": \"fail\", \"message\": \"eid=2&text=ID Not Valid&pid=5\"}}\r\n"])?;
+        assert!(matches!(
+            heos.play_next(5),
+            Err(Error::Heos(e)) if e == "player/play_next failed: ID Not Valid"
+        ));
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_reply_is_returned() -> Result<(), io::Error> {
+        let (server, mut heos) = connect(vec!["blub\r\n"])?;
+        assert!(
+            matches!(heos.players(), Err(Error::Heos(e)) if e.starts_with("invalid reply 'blub'"))
+        );
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn play_state_display_and_parse() {
+        assert_eq!("pause", PlayState::Pause.to_string());
+        assert!(matches!("stop".parse(), Ok(PlayState::Stop)));
+        assert!(matches!("blub".parse::<PlayState>(), Err(Error::Heos(_))));
+    }
+
+    #[test]
+    fn params_are_escaped() {
+        assert_eq!("a%26b%3Dc%25", encode_param("a&b=c%"));
+        assert_eq!("a&b=c%", decode_param("a%26b%3Dc%25"));
+    }
+}
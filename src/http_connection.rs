@@ -0,0 +1,476 @@
+use crate::command::command;
+use crate::connection::Connection;
+use crate::error::Error;
+use crate::logger::Logger;
+use crate::model::Model;
+use crate::state::{SetState, State, StateValue};
+use crate::stream::{connect, TcpOptions};
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const STATUS_PATH: &str = "/goform/formMainZone_MainZoneXml.xml";
+const COMMAND_PATH: &str = "/goform/formiPhoneAppDirect.xml";
+// the web interface answers slower than telnet
+const TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// the web interface reports the volume in dB, where -80 dB is volume 0
+const VOLUME_OFFSET_DB: f64 = 80.0;
+
+/// Controls the receiver through its web interface, which unlike telnet
+/// may be used by several clients at the same time.
+#[derive(Clone)]
+pub struct HttpConnection {
+    host: String,
+    port: u16,
+    model: &'static Model,
+    logger: Arc<dyn Logger>,
+    parse_errors: usize,
+    tcp: TcpOptions,
+    // how long a request waits for the answer
+    query_timeout: Duration,
+}
+
+// escapes everything in the command, which could end the query string early
+fn encode_query(command: &str) -> String {
+    command
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "?-._~".contains(c) {
+                c.to_string()
+            } else {
+                c.to_string()
+                    .bytes()
+                    .map(|b| format!("%{:02X}", b))
+                    .collect()
+            }
+        })
+        .collect()
+}
+
+/// Returns the content of `<tag><value>...</value></tag>`.
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", tag))?;
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+    let element = &xml[start..end];
+    let value_start = element.find("<value>")? + "<value>".len();
+    let value_end = element.find("</value>")?;
+    element.get(value_start..value_end).map(str::trim)
+}
+
+fn xml_tag(state: State) -> Option<&'static str> {
+    command(state).xml_tag
+}
+
+impl HttpConnection {
+    pub fn new(
+        host: &str,
+        port: u16,
+        logger: Arc<dyn Logger>,
+        model: &'static Model,
+    ) -> HttpConnection {
+        HttpConnection {
+            host: host.to_string(),
+            port,
+            model,
+            logger,
+            parse_errors: 0,
+            tcp: TcpOptions::default(),
+            query_timeout: TIMEOUT,
+        }
+    }
+
+    /// The read timeout of `tcp` is replaced by the query timeout.
+    pub fn set_tcp_options(&mut self, tcp: TcpOptions) {
+        self.tcp = tcp;
+    }
+
+    /// How long a request waits for the answer of the receiver.
+    pub fn set_query_timeout(&mut self, query_timeout: Duration) {
+        self.query_timeout = query_timeout;
+    }
+
+    fn http_get(&self, path: &str) -> Result<String, io::Error> {
+        let tcp = TcpOptions {
+            read_timeout: Some(self.query_timeout),
+            ..self.tcp
+        };
+        let mut stream = connect(&self.host, self.port, &tcp)?;
+        let request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, self.host
+        );
+        stream.write_all(request.as_bytes())?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+        let status_line = head.lines().next().unwrap_or_default();
+        if status_line.split_whitespace().nth(1) != Some("200") {
+            return Err(io::Error::other(format!(
+                "receiver answered with '{}'",
+                status_line
+            )));
+        }
+        Ok(body.to_string())
+    }
+
+    fn send_command(&self, command: &str) -> Result<String, io::Error> {
+        self.http_get(&format!("{}?{}", COMMAND_PATH, encode_query(command)))
+    }
+
+    fn parse_value(&mut self, state: State, value: &str) -> StateValue {
+        // the receiver shows -- for the volume while in standby
+        if value == "--" {
+            return StateValue::Unknown;
+        }
+        let parsed = match state {
+            State::MainVolume => value
+                .parse::<f64>()
+                .map(|db| {
+                    StateValue::Integer(((db + VOLUME_OFFSET_DB).max(0.0) * 10.0).round() as u32)
+                })
+                .map_err(|_| "a volume in dB"),
+            _ => command(state)
+                .parse_received(value, self.model)
+                .map(|sstate| sstate.convert().1),
+        };
+        parsed.unwrap_or_else(|expected| {
+            self.parse_errors += 1;
+            self.logger.log(&format!(
+                "skipping value: invalid value '{}' of {}, expected {}",
+                value, state, expected
+            ));
+            StateValue::Unknown
+        })
+    }
+}
+
+impl Connection for HttpConnection {
+    fn clone_box(&self) -> Box<dyn Connection> {
+        Box::new(self.clone())
+    }
+
+    fn query_timeout(&self) -> Duration {
+        self.query_timeout
+    }
+
+    fn get(&mut self, state: State) -> Result<StateValue, Error> {
+        Ok(self.query_many(&[state])?[0])
+    }
+
+    /// All states are read from a single status page.
+    fn query_many(&mut self, states: &[State]) -> Result<Vec<StateValue>, Error> {
+        if states.iter().all(|state| xml_tag(*state).is_none()) {
+            return Ok(vec![StateValue::Unknown; states.len()]);
+        }
+        let status = self.http_get(STATUS_PATH)?;
+        Ok(states
+            .iter()
+            .map(
+                |state| match xml_tag(*state).and_then(|tag| xml_value(&status, tag)) {
+                    Some(value) => self.parse_value(*state, value),
+                    None => StateValue::Unknown,
+                },
+            )
+            .collect())
+    }
+
+    fn set(&mut self, sstate: SetState) -> Result<(), io::Error> {
+        self.send_command(&self.model.encode(&sstate)).map(|_| ())
+    }
+
+    /// Polls the status page, which may show the old state for a moment.
+    fn set_confirmed(&mut self, sstate: SetState, timeout: Duration) -> Result<StateValue, Error> {
+        self.set(sstate)?;
+        let deadline = Instant::now() + timeout;
+        let state = sstate.convert().0;
+        let expected = self.model.echo(&sstate);
+        loop {
+            let value = self.get(state)?;
+            if value == expected {
+                return Ok(value);
+            }
+            if Instant::now() >= deadline {
+                return Err(match value {
+                    StateValue::Unknown => Error::Unconfirmed(sstate),
+                    _ => Error::Rejected(sstate, value),
+                });
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// The web interface answers immediately, so `wait` is not needed.
+    fn send_raw(&mut self, command: &str, _wait: Duration) -> Result<Vec<String>, io::Error> {
+        Ok(self
+            .send_command(command)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect())
+    }
+
+    fn model(&self) -> &'static Model {
+        self.model
+    }
+
+    /// Only states on the status page can be read.
+    fn supports(&self, state: State) -> bool {
+        self.model.supports(state) && xml_tag(state).is_some()
+    }
+
+    fn parse_errors(&self) -> usize {
+        self.parse_errors
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{encode_query, xml_value, HttpConnection};
+    use crate::connection::Connection;
+    use crate::error::Error;
+    use crate::logger::{nothing, MockLogger};
+    use crate::model::{default_model, find_model};
+    use crate::state::{PowerState, SetState, SourceInputState, State, StateValue};
+    use predicates::ord::eq;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    pub const STATUS: &str = "<?xml version=\"1.0\" encoding=\"utf-8\" ?>\n<item>\n<Power><value>ON</value></Power>\n<InputFuncSelect><value>TV</value></InputFuncSelect>\n<MasterVolume><value>-40.5</value></MasterVolume>\n<Mute><value>off</value></Mute>\n</item>\n";
+
+    type StandIn = JoinHandle<Result<Vec<String>, io::Error>>;
+
+    /// Answers each of `requests` with `status` and `body` and returns the
+    /// request lines.
+    pub fn create_http_stand_in(
+        requests: usize,
+        status: &'static str,
+        body: &'static str,
+    ) -> Result<(StandIn, u16), io::Error> {
+        let listen_socket = TcpListener::bind("localhost:0")?;
+        let local_port = listen_socket.local_addr()?.port();
+
+        let server = thread::spawn(move || -> Result<Vec<String>, io::Error> {
+            let mut request_lines = Vec::new();
+            for _ in 0..requests {
+                let mut stream = listen_socket.accept()?.0;
+                let mut reader = BufReader::new(stream.try_clone()?);
+                let mut line = String::new();
+                reader.read_line(&mut line)?;
+                request_lines.push(line.trim().to_string());
+                // skip the headers
+                loop {
+                    line.clear();
+                    if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                        break;
+                    }
+                }
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: text/xml\r\n\r\n{}",
+                    status, body
+                )?;
+            }
+            Ok(request_lines)
+        });
+
+        Ok((server, local_port))
+    }
+
+    fn connect(port: u16) -> HttpConnection {
+        HttpConnection::new(
+            "localhost",
+            port,
+            Arc::new(MockLogger::new()),
+            find_model("AVR-X").unwrap(),
+        )
+    }
+
+    #[test]
+    fn xml_value_test() {
+        assert_eq!(Some("ON"), xml_value(STATUS, "Power"));
+        assert_eq!(Some("-40.5"), xml_value(STATUS, "MasterVolume"));
+        assert_eq!(None, xml_value(STATUS, "ZonePower"));
+        assert_eq!(None, xml_value("<Power>ON</Power>", "Power"));
+    }
+
+    #[test]
+    fn encode_query_test() {
+        assert_eq!("PWON", encode_query("PWON"));
+        assert_eq!("PW?", encode_query("PW?"));
+        assert_eq!("SITV%20AUDIO", encode_query("SITV AUDIO"));
+        assert_eq!("SINET%2FUSB", encode_query("SINET/USB"));
+    }
+
+    #[test]
+    fn get_reads_status_page() -> Result<(), Error> {
+        let (server, port) = create_http_stand_in(3, "200 OK", STATUS)?;
+        let mut hc = connect(port);
+        assert_eq!(StateValue::Power(PowerState::On), hc.get(State::Power)?);
+        assert_eq!(
+            StateValue::SourceInput(SourceInputState::TvAudio),
+            hc.get(State::SourceInput)?
+        );
+        assert_eq!(StateValue::Integer(395), hc.get(State::MainVolume)?);
+        assert_eq!(StateValue::Unknown, hc.get(State::MaxVolume)?);
+        assert_eq!(
+            vec!["GET /goform/formMainZone_MainZoneXml.xml HTTP/1.0"; 3],
+            server.join().unwrap()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn query_many_reads_status_page_once() -> Result<(), Error> {
+        let (server, port) = create_http_stand_in(1, "200 OK", STATUS)?;
+        let mut hc = connect(port);
+        assert_eq!(
+            vec![
+                StateValue::Power(PowerState::On),
+                StateValue::Integer(395),
+                StateValue::Unknown
+            ],
+            hc.query_many(&[State::Power, State::MainVolume, State::MaxVolume])?
+        );
+        assert_eq!(
+            vec!["GET /goform/formMainZone_MainZoneXml.xml HTTP/1.0"],
+            server.join().unwrap()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn get_counts_invalid_values() -> Result<(), Error> {
+        let (server, port) = create_http_stand_in(
+            2,
+            "200 OK",
+            "<MasterVolume><value>loud</value></MasterVolume>",
+        )?;
+        let mut logger = MockLogger::new();
+        logger
+            .expect_log()
+            .times(2)
+            .with(eq(
+                "skipping value: invalid value 'loud' of MV, expected a volume in dB",
+            ))
+            .returning(nothing);
+        let mut hc = HttpConnection::new("localhost", port, Arc::new(logger), default_model());
+        assert_eq!(StateValue::Unknown, hc.get(State::MainVolume)?);
+        assert_eq!(StateValue::Unknown, hc.get(State::MainVolume)?);
+        assert_eq!(2, hc.parse_errors());
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn set_sends_encoded_command() -> Result<(), io::Error> {
+        let (server, port) = create_http_stand_in(2, "200 OK", "")?;
+        let mut hc = connect(port);
+        hc.set(SetState::SourceInput(SourceInputState::TvAudio))?;
+        hc.set(SetState::MainVolume(200))?;
+        assert_eq!(
+            vec![
+                "GET /goform/formiPhoneAppDirect.xml?SITV HTTP/1.0",
+                "GET /goform/formiPhoneAppDirect.xml?MV20 HTTP/1.0"
+            ],
+            server.join().unwrap()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn set_confirmed_checks_status_page() -> Result<(), Error> {
+        let (server, port) = create_http_stand_in(4, "200 OK", STATUS)?;
+        let mut hc = connect(port);
+        assert_eq!(
+            StateValue::Power(PowerState::On),
+            hc.set_confirmed(SetState::Power(PowerState::On), Duration::ZERO)?
+        );
+        assert!(matches!(
+            hc.set_confirmed(SetState::Power(PowerState::Standby), Duration::ZERO),
+            Err(Error::Rejected(
+                SetState::Power(PowerState::Standby),
+                StateValue::Power(PowerState::On)
+            ))
+        ));
+        assert_eq!(
+            vec![
+                "GET /goform/formiPhoneAppDirect.xml?PWON HTTP/1.0",
+                "GET /goform/formMainZone_MainZoneXml.xml HTTP/1.0",
+                "GET /goform/formiPhoneAppDirect.xml?PWSTANDBY HTTP/1.0",
+                "GET /goform/formMainZone_MainZoneXml.xml HTTP/1.0",
+            ],
+            server.join().unwrap()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn set_confirmed_accepts_volume_in_whole_steps() -> Result<(), Error> {
+        let (server, port) = create_http_stand_in(
+            2,
+            "200 OK",
+            "<MasterVolume><value>-35.0</value></MasterVolume>",
+        )?;
+        let mut hc = connect(port);
+        assert_eq!(
+            StateValue::Integer(450),
+            hc.set_confirmed(SetState::MainVolume(450), Duration::ZERO)?
+        );
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn send_raw_returns_body() -> Result<(), io::Error> {
+        let (server, port) = create_http_stand_in(1, "200 OK", "PSBAS 50\r\n\r\n")?;
+        let mut hc = connect(port);
+        assert_eq!(
+            vec!["PSBAS 50"],
+            hc.send_raw("PSBAS 50", Duration::from_millis(0))?
+        );
+        assert_eq!(
+            vec!["GET /goform/formiPhoneAppDirect.xml?PSBAS%2050 HTTP/1.0"],
+            server.join().unwrap()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unanswered_request_times_out() -> Result<(), Error> {
+        let listen_socket = TcpListener::bind("localhost:0")?;
+        let mut hc = connect(listen_socket.local_addr()?.port());
+        hc.set_query_timeout(Duration::from_millis(50));
+        let _connected = thread::spawn(move || listen_socket.accept());
+        assert!(matches!(hc.get(State::Power), Err(Error::IO(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn supports_only_states_of_status_page() {
+        let hc = connect(0);
+        assert!(hc.supports(State::Power));
+        assert!(hc.supports(State::MainVolume));
+        assert!(!hc.supports(State::MaxVolume));
+    }
+
+    #[test]
+    fn error_status_is_returned() -> Result<(), io::Error> {
+        let (server, port) = create_http_stand_in(1, "404 Not Found", "")?;
+        let mut hc = connect(port);
+        let error = hc.set(SetState::Power(PowerState::On)).unwrap_err();
+        assert_eq!(
+            "receiver answered with 'HTTP/1.1 404 Not Found'",
+            error.to_string()
+        );
+        server.join().unwrap()?;
+        Ok(())
+    }
+}
@@ -1,29 +1,71 @@
+//! Controls Denon receivers over telnet, HTTP or a serial port. Embedding
+//! applications use `Receiver`, which the `denon-control` tool is built on.
+
 // $ printf "MV53\r" | nc -i 1 0005cd221b08.lan 23 | stdbuf -o 0 tr "\r" "\n"
 // MV53
 // MVMAX 86
 
+#[cfg(feature = "async")]
+mod async_connection;
 mod avahi;
 mod avahi3;
 mod avahi_error;
+mod builder;
+mod charset;
+mod codec;
+mod command;
+mod command_queue;
+mod connection;
 mod denon_connection;
 mod error;
+mod heos;
+mod http_connection;
 mod logger;
+mod model;
 mod parse;
+mod receiver;
+mod serial;
 mod state;
 mod stream;
 
-pub use denon_connection::read;
-use denon_connection::DenonConnection;
+#[cfg(feature = "async")]
+pub use async_connection::{discover, AsyncDenonConnection};
+pub use builder::ConnectionBuilder;
+use charset::DisplayLine;
+pub use command::Zone;
+use command::{commands, commands_listing, queryable_commands, Command};
+pub use command_queue::Pacing;
+pub use denon_connection::{Backoff, Change, ConnectionEvent, Health, Origin, Received};
 pub use error::Error;
 use getopts::Options;
-use logger::Logger;
-pub use logger::StdoutLogger;
-use state::{get_state, PowerState, SetState, SourceInputState, State};
-use std::rc::Rc;
-pub use stream::create_tcp_stream;
-use stream::ConnectionStream;
+use heos::{HeosConnection, PlayState, Player, HEOS_PORT};
+pub use logger::{Logger, StdoutLogger};
+use model::models;
+pub use model::{default_model, find_model, Model};
+pub use receiver::Receiver;
+use serial::{create_serial_stream, SerialSettings};
+pub use state::{PowerState, SetState, SourceInputState, State, StateValue};
+use std::sync::Arc;
+use std::time::Duration;
+use stream::{create_tcp_stream_with, ConnectionStream, TcpOptions};
 
 type GetReceiverFn = fn(&dyn Logger) -> Result<String, avahi_error::Error>;
+type GetModelFn = fn(&dyn Logger) -> Result<String, avahi_error::Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transport {
+    Telnet,
+    Http,
+}
+
+impl Transport {
+    fn default_port(&self) -> u16 {
+        match self {
+            Transport::Telnet => 23,
+            Transport::Http => 80,
+        }
+    }
+}
 
 // status object shall get the current status of the avr 1912
 // easiest way would be a map<Key, Value> where Value is an enum of u32 and String
@@ -31,23 +73,126 @@ type GetReceiverFn = fn(&dyn Logger) -> Result<String, avahi_error::Error>;
 // the status object can be shared or the communication thread can be asked about a
 // status which queries the receiver if it is not set
 
-pub fn parse_args(args: Vec<String>, logger: &dyn Logger) -> getopts::Matches {
+fn parse_args(args: Vec<String>, logger: &dyn Logger) -> getopts::Matches {
     let mut ops = Options::new();
     ops.optopt(
         "a",
         "address",
-        "Address of Denon AVR with optional port (default: 23)",
+        "Address of Denon AVR with optional port (default: 23, 80 with --transport http, 1255 for heos)",
         "HOSTNAME[:port]",
     );
-    ops.optopt("p", "power", "Power ON, STANDBY or OFF", "POWER_MODE");
-    ops.optopt("v", "volume", "set volume in range 30..50", "VOLUME");
-    ops.optopt("i", "input", "set source input: DVD, GAME2", "SOURCE_INPUT");
+    ops.optopt(
+        "t",
+        "transport",
+        "talk to the receiver via telnet or its web interface (default: telnet)",
+        "telnet|http",
+    );
+    for command in commands() {
+        if let Some(option) = command.option {
+            ops.optopt(option.short, option.long, &command.help(), option.hint);
+        }
+    }
     ops.optflag(
         "e",
         "extern-avahi",
         "use avahi-browser to find receiver instead of library",
     );
+    ops.optopt(
+        "m",
+        "model",
+        &format!(
+            "model or profile of the receiver ({}), auto to detect it (default: {})",
+            models()
+                .iter()
+                .map(|m| m.name)
+                .collect::<Vec<&str>>()
+                .join(", "),
+            default_model().name
+        ),
+        "MODEL",
+    );
     ops.optflag("s", "status", "print status of receiver");
+    ops.optflag(
+        "",
+        "age",
+        "show how long ago the values of --status were received",
+    );
+    ops.optflag(
+        "",
+        "verify",
+        "wait until the receiver reports the new states and fail after --timeout otherwise",
+    );
+    ops.optmulti(
+        "r",
+        "raw",
+        "send COMMAND unmodified and print the replies, e.g. \"PSBAS 50\"",
+        "COMMAND",
+    );
+    ops.optmulti(
+        "q",
+        "query",
+        "send query COMMAND and print the replies, e.g. \"PS?\"",
+        "COMMAND",
+    );
+    ops.optopt(
+        "w",
+        "wait",
+        "time to wait for replies of --raw and --query (default: 200)",
+        "MILLISECONDS",
+    );
+    ops.optopt(
+        "",
+        "serial",
+        "control the receiver via RS-232 instead of the network",
+        "DEVICE",
+    );
+    ops.optopt(
+        "",
+        "baud",
+        "baud rate of the serial port (default: 9600)",
+        "RATE",
+    );
+    ops.optopt(
+        "",
+        "player",
+        "HEOS player to control by its id or name (default: first player)",
+        "PLAYER",
+    );
+    ops.optopt(
+        "",
+        "pause",
+        "pause between commands to the receiver (default: 50)",
+        "MILLISECONDS",
+    );
+    ops.optflag(
+        "",
+        "reconnect",
+        "connect again with increasing delays if the connection is lost",
+    );
+    ops.optopt(
+        "",
+        "connect-timeout",
+        "time to wait for the connection to the receiver (default: 5000)",
+        "MILLISECONDS",
+    );
+    ops.optopt(
+        "",
+        "timeout",
+        "time to wait for answers of the receiver to queries and for --verify (default: 500, 5000 with --transport http)",
+        "MILLISECONDS",
+    );
+    ops.optopt(
+        "",
+        "read-timeout",
+        "time after which a read from the telnet socket gives up and the reader wakes up, which is not the --timeout of queries (default: none)",
+        "MILLISECONDS",
+    );
+    ops.optopt(
+        "",
+        "keepalive",
+        "probe the idle connection to notice a switched off receiver",
+        "SECONDS",
+    );
     ops.optflag("h", "help", "print help");
 
     let arguments = match ops.parse(&args[1..]) {
@@ -58,8 +203,18 @@ pub fn parse_args(args: Vec<String>, logger: &dyn Logger) -> getopts::Matches {
     };
 
     if arguments.opt_present("h") {
-        let brief = format!("Usage: {} [options]", args[0]);
-        logger.log(&brief);
+        let brief = format!(
+            "Usage: {} [options] [commands | heos {}]",
+            args[0],
+            HEOS_ACTIONS.join("|")
+        );
+        logger.log(&ops.usage(&brief));
+        let exit_success: i32 = 0;
+        std::process::exit(exit_success);
+    }
+
+    if arguments.free.iter().any(|free| free == "commands") {
+        logger.log(&commands_listing());
         let exit_success: i32 = 0;
         std::process::exit(exit_success);
     }
@@ -67,17 +222,38 @@ pub fn parse_args(args: Vec<String>, logger: &dyn Logger) -> getopts::Matches {
     arguments
 }
 
-fn print_status(dc: &mut DenonConnection) -> Result<String, std::io::Error> {
-    Ok(format!(
-        "Current status of receiver:\n\tPower({})\n\tSourceInput({})\n\tMainVolume({})\n\tMaxVolume({})\n",
-        dc.get(State::Power)?,
-        dc.get(State::SourceInput)?,
-        dc.get(State::MainVolume)?,
-        dc.get(State::MaxVolume)?
-    ))
+fn print_status(receiver: &mut Receiver, show_age: bool) -> Result<String, Error> {
+    let mut status = String::from("Current status of receiver:\n");
+    let queryable: Vec<&Command> = queryable_commands(receiver.model())
+        .filter(|c| receiver.supports(c.state))
+        .collect();
+    let states: Vec<State> = queryable.iter().map(|c| c.state).collect();
+    for (command, value) in queryable.iter().zip(receiver.get_many(&states)?) {
+        status += &format!("\t{}({})", command.name, value);
+        if let Some(age) = receiver.age(command.state).filter(|_| show_age) {
+            status += &format!(" {}s ago", age.as_secs());
+        }
+        status += "\n";
+    }
+    Ok(status)
+}
+
+fn print_replies(
+    receiver: &mut Receiver,
+    command: &str,
+    wait: Duration,
+    logger: &dyn Logger,
+) -> Result<(), Error> {
+    for line in receiver.send_raw(command, wait)? {
+        match DisplayLine::parse(&line) {
+            Some(display_line) => logger.log(&display_line.to_string()),
+            None => logger.log(&line),
+        }
+    }
+    Ok(())
 }
 
-pub fn get_avahi_impl(args: &getopts::Matches) -> GetReceiverFn {
+fn get_avahi_impl(args: &getopts::Matches) -> GetReceiverFn {
     if args.opt_present("e") {
         avahi::get_receiver
     } else {
@@ -85,12 +261,136 @@ pub fn get_avahi_impl(args: &getopts::Matches) -> GetReceiverFn {
     }
 }
 
-pub fn get_receiver_and_port(
+fn get_model_impl(args: &getopts::Matches) -> GetModelFn {
+    if args.opt_present("e") {
+        avahi::get_model
+    } else {
+        avahi3::get_model
+    }
+}
+
+fn get_model(
+    args: &getopts::Matches,
+    logger: &dyn Logger,
+    get_mod: GetModelFn,
+) -> Result<&'static Model, Error> {
+    match args.opt_str("m") {
+        None => Ok(default_model()),
+        Some(name) if name == "auto" => match get_mod(logger) {
+            Ok(detected) => {
+                let model = find_model(&detected).unwrap_or(default_model());
+                logger.log(&format!("detected {}, using {}", detected, model.name));
+                Ok(model)
+            }
+            Err(e) => {
+                logger.log(&format!(
+                    "could not detect model: {}, using {}",
+                    e,
+                    default_model().name
+                ));
+                Ok(default_model())
+            }
+        },
+        Some(name) => find_model(&name)
+            .ok_or_else(|| Error::Input(format!("given model {} is not known", name))),
+    }
+}
+
+const HEOS_ACTIONS: [&str; 14] = [
+    "players",
+    "groups",
+    "group create PLAYER PLAYER...",
+    "group remove PLAYER",
+    "queue",
+    "play-url URL",
+    "now-playing",
+    "state",
+    "play",
+    "pause",
+    "stop",
+    "next",
+    "previous",
+    "volume [LEVEL]",
+];
+
+/// Whether the HEOS CLI shall be used instead of the Denon protocol.
+fn is_heos(args: &getopts::Matches) -> bool {
+    args.free.first().is_some_and(|free| free == "heos")
+}
+
+fn get_transport(args: &getopts::Matches) -> Result<Transport, Error> {
+    match args.opt_str("t").as_deref() {
+        None | Some("telnet") => Ok(Transport::Telnet),
+        Some("http") => Ok(Transport::Http),
+        Some(other) => Err(Error::Input(format!(
+            "given transport {} is not known, use telnet or http",
+            other
+        ))),
+    }
+}
+
+fn get_serial_settings(args: &getopts::Matches) -> Result<SerialSettings, Error> {
+    let default = SerialSettings::default();
+    Ok(SerialSettings {
+        baud_rate: args.opt_get_default("baud", default.baud_rate)?,
+        ..default
+    })
+}
+
+fn get_pacing(args: &getopts::Matches) -> Result<Pacing, Error> {
+    let default = Pacing::default();
+    Ok(Pacing {
+        interval: Duration::from_millis(
+            args.opt_get_default("pause", default.interval.as_millis() as u64)?,
+        ),
+        ..default
+    })
+}
+
+fn get_tcp_options(args: &getopts::Matches) -> Result<TcpOptions, Error> {
+    let default = TcpOptions::default();
+    let connect_timeout = default.connect_timeout.unwrap_or_default();
+    Ok(TcpOptions {
+        connect_timeout: Some(Duration::from_millis(
+            args.opt_get_default("connect-timeout", connect_timeout.as_millis() as u64)?,
+        )),
+        read_timeout: args.opt_get("read-timeout")?.map(Duration::from_millis),
+        keepalive: args.opt_get("keepalive")?.map(Duration::from_secs),
+    })
+}
+
+/// `None` keeps the default of the transport.
+fn get_query_timeout(args: &getopts::Matches) -> Result<Option<Duration>, Error> {
+    Ok(args.opt_get("timeout")?.map(Duration::from_millis))
+}
+
+fn get_builder(
+    args: &getopts::Matches,
+    model: &'static Model,
+    logger: Arc<dyn Logger>,
+) -> Result<ConnectionBuilder, Error> {
+    let mut builder = ConnectionBuilder::new(model)
+        .tcp_options(get_tcp_options(args)?)
+        .pacing(get_pacing(args)?)
+        .logger(logger);
+    if let Some(timeout) = get_query_timeout(args)? {
+        builder = builder.query_timeout(timeout);
+    }
+    Ok(builder)
+}
+
+fn get_receiver_and_port(
     args: &getopts::Matches,
     logger: &dyn Logger,
     get_rec: GetReceiverFn,
 ) -> Result<(String, u16), avahi_error::Error> {
-    let default_port = 23;
+    let default_port = if is_heos(args) {
+        HEOS_PORT
+    } else {
+        get_transport(args)
+            .unwrap_or(Transport::Telnet)
+            .default_port()
+    };
     let (denon_name, port) = match args.opt_str("a") {
         Some(name) => match name.find(':') {
             Some(pos) => (
@@ -105,48 +405,235 @@ pub fn get_receiver_and_port(
     Ok((denon_name, port))
 }
 
-pub fn main2(
+fn main2(
     args: getopts::Matches,
     stream: Box<dyn ConnectionStream>,
     logger: Box<dyn Logger>,
+    model: &'static Model,
+) -> Result<(), Error> {
+    let rclogger: Arc<dyn Logger> = logger.into();
+    let mut receiver = get_builder(&args, model, rclogger.clone())?.open(stream)?;
+    run(args, &mut receiver, rclogger.as_ref())
+}
+
+fn main_reconnecting(
+    args: getopts::Matches,
+    denon_name: &str,
+    denon_port: u16,
+    logger: Box<dyn Logger>,
+    model: &'static Model,
 ) -> Result<(), Error> {
-    let rclogger: Rc<dyn Logger> = logger.into();
-    let mut dc = DenonConnection::new(stream, rclogger.clone())?;
+    let rclogger: Arc<dyn Logger> = logger.into();
+    let mut receiver = get_builder(&args, model, rclogger.clone())?
+        .reconnect(Backoff::default())
+        .connect(denon_name, denon_port)?;
+    run(args, &mut receiver, rclogger.as_ref())
+}
+
+fn main_http(
+    args: getopts::Matches,
+    denon_name: &str,
+    denon_port: u16,
+    logger: Box<dyn Logger>,
+    model: &'static Model,
+) -> Result<(), Error> {
+    let rclogger: Arc<dyn Logger> = logger.into();
+    let mut receiver = get_builder(&args, model, rclogger.clone())?.http(denon_name, denon_port);
+    run(args, &mut receiver, rclogger.as_ref())
+}
+
+fn find_player(players: &[Player], wanted: &str) -> Result<i64, Error> {
+    players
+        .iter()
+        .find(|p| p.pid.to_string() == wanted || p.name.eq_ignore_ascii_case(wanted))
+        .map(|p| p.pid)
+        .ok_or_else(|| Error::Heos(format!("no HEOS player {} found", wanted)))
+}
+
+fn heos_player(args: &getopts::Matches, heos: &mut HeosConnection) -> Result<i64, Error> {
+    let players = heos.players()?;
+    match args.opt_str("player") {
+        Some(wanted) => find_player(&players, &wanted),
+        None => players
+            .first()
+            .map(|p| p.pid)
+            .ok_or_else(|| Error::Heos(String::from("no HEOS player found"))),
+    }
+}
+
+fn main_heos(
+    args: getopts::Matches,
+    stream: Box<dyn ConnectionStream>,
+    logger: Box<dyn Logger>,
+) -> Result<(), Error> {
+    let mut heos = HeosConnection::new(stream)?;
+    if let Some(timeout) = get_query_timeout(&args)? {
+        heos.set_timeout(timeout);
+    }
+    let action: Vec<&str> = args.free.iter().skip(1).map(String::as_str).collect();
+    match action.as_slice() {
+        ["players"] => {
+            for player in heos.players()? {
+                logger.log(&format!(
+                    "{}\t{}\t{}",
+                    player.pid, player.name, player.model
+                ));
+            }
+            return Ok(());
+        }
+        ["groups"] => {
+            let groups: Vec<serde_json::Value> =
+                heos.groups()?.iter().map(|g| g.to_json()).collect();
+            logger.log(&serde_json::Value::from(groups).to_string());
+            return Ok(());
+        }
+        ["group", "create", names @ ..] if names.len() > 1 => {
+            let players = heos.players()?;
+            let pids = names
+                .iter()
+                .map(|name| find_player(&players, name))
+                .collect::<Result<Vec<i64>, Error>>()?;
+            let gid = heos.set_group(&pids)?;
+            logger.log(&serde_json::json!({ "gid": gid, "pids": pids }).to_string());
+            return Ok(());
+        }
+        ["group", "remove", name] => {
+            let pid = find_player(&heos.players()?, name)?;
+            heos.set_group(&[pid])?;
+            logger.log(&serde_json::json!({ "removed": pid }).to_string());
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let pid = heos_player(&args, &mut heos)?;
+    match action.as_slice() {
+        ["now-playing"] => {
+            let media = heos.now_playing(pid)?;
+            logger.log(&format!(
+                "type: {}\nsong: {}\nartist: {}\nalbum: {}\nstation: {}",
+                media.media_type, media.song, media.artist, media.album, media.station
+            ));
+        }
+        ["state"] => logger.log(&heos.play_state(pid)?.to_string()),
+        ["play"] => heos.set_play_state(pid, PlayState::Play)?,
+        ["pause"] => heos.set_play_state(pid, PlayState::Pause)?,
+        ["stop"] => heos.set_play_state(pid, PlayState::Stop)?,
+        ["next"] => heos.play_next(pid)?,
+        ["previous"] => heos.play_previous(pid)?,
+        ["volume"] => logger.log(&heos.volume(pid)?.to_string()),
+        ["volume", level] => heos.set_volume(pid, level.parse()?)?,
+        ["queue"] => {
+            let queue: Vec<serde_json::Value> =
+                heos.queue(pid)?.iter().map(|item| item.to_json()).collect();
+            logger.log(&serde_json::Value::from(queue).to_string());
+        }
+        ["play-url", url] => {
+            heos.play_stream(pid, url)?;
+            logger.log(&serde_json::json!({ "pid": pid, "url": url }).to_string());
+        }
+        _ => {
+            return Err(Error::Input(format!(
+                "unknown heos action {}, use one of: {}",
+                action.join(" "),
+                HEOS_ACTIONS.join(", ")
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// Runs the `denon-control` tool with the command line `args`. Only the tool
+/// uses this, so it is not part of the API.
+#[doc(hidden)]
+pub fn cli(args: Vec<String>, logger: Box<dyn Logger>) -> Result<(), Error> {
+    let args = parse_args(args, &*logger);
+    if let Some(device) = args.opt_str("serial") {
+        let model = get_model(&args, &*logger, get_model_impl(&args))?;
+        let s = create_serial_stream(&device, get_serial_settings(&args)?)?;
+        return main2(args, s, logger, model);
+    }
+    let transport = get_transport(&args)?;
+    let (denon_name, denon_port) = get_receiver_and_port(&args, &*logger, get_avahi_impl(&args))?;
+    if is_heos(&args) {
+        let tcp_options = get_tcp_options(&args)?;
+        let s = create_tcp_stream_with(denon_name.as_str(), denon_port, &tcp_options)?;
+        return main_heos(args, s, logger);
+    }
+    let model = get_model(&args, &*logger, get_model_impl(&args))?;
+    match transport {
+        Transport::Telnet if args.opt_present("reconnect") => {
+            main_reconnecting(args, &denon_name, denon_port, logger, model)
+        }
+        Transport::Telnet => {
+            let tcp_options = get_tcp_options(&args)?;
+            let s = create_tcp_stream_with(denon_name.as_str(), denon_port, &tcp_options)?;
+            main2(args, s, logger, model)
+        }
+        Transport::Http => main_http(args, &denon_name, denon_port, logger, model),
+    }
+}
 
+fn run(args: getopts::Matches, receiver: &mut Receiver, logger: &dyn Logger) -> Result<(), Error> {
+    let model = receiver.model();
     if args.opt_present("s") {
-        rclogger.log(&print_status(&mut dc)?);
+        logger.log(&print_status(receiver, args.opt_present("age"))?);
     }
-    if let Some(p) = args.opt_str("p") {
-        let state = get_state(PowerState::states(), p.as_str())?;
-        dc.set(SetState::Power(state))?;
+    for command in commands() {
+        if let Some(value) = command.option.and_then(|o| args.opt_str(o.long)) {
+            let sstate = command.parse_input(&value, model)?;
+            if args.opt_present("verify") {
+                let confirmed = receiver.set_confirmed(sstate, receiver.query_timeout())?;
+                logger.log(&format!("{}({}) confirmed", command.name, confirmed));
+            } else {
+                receiver.set(sstate)?;
+            }
+        }
     }
-    if let Some(i) = args.opt_str("i") {
-        let state = get_state(SourceInputState::states(), i.as_str())?;
-        dc.set(SetState::SourceInput(state))?;
+    let wait = Duration::from_millis(args.opt_get_default("w", 200)?);
+    for raw in args.opt_strs("r") {
+        print_replies(receiver, &raw, wait, logger)?;
     }
-    if let Some(mut vi) = args.opt_get::<u32>("v")? {
-        // do not accidentally kill the ears
-        if vi > 50 {
-            vi = 50;
+    for mut query in args.opt_strs("q") {
+        if !query.ends_with('?') {
+            query.push('?');
         }
-        dc.set(SetState::MainVolume(vi))?;
+        print_replies(receiver, &query, wait, logger)?;
+    }
+    if receiver.parse_errors() > 0 {
+        logger.log(&format!(
+            "skipped {} malformed lines from receiver",
+            receiver.parse_errors()
+        ));
     }
     Ok(())
 }
 
 #[cfg(test)]
 mod test {
-    use crate::denon_connection::{read, test::create_connected_connection, write_string};
+    use crate::denon_connection::test::{create_connected_connection, read_lines};
+    use crate::denon_connection::write_string;
     use crate::error::Error;
+    use crate::heos::test::{create_fake_heos_server, GROUPS, PLAYERS};
+    use crate::http_connection::test::{create_http_stand_in, STATUS};
     use crate::logger::{nothing, MockLogger};
+    use crate::model::{default_model, find_model};
+    use crate::serial::test::open_pty;
+    use crate::serial::{create_serial_stream, SerialSettings};
     use crate::state::{PowerState, SetState, SourceInputState, State};
     use crate::stream::{create_tcp_stream, MockReadStream, MockShutdownStream};
+    use crate::Transport;
     use crate::{avahi, avahi3, avahi_error, GetReceiverFn};
-    use crate::{get_avahi_impl, get_receiver_and_port, main2, parse_args, print_status};
+    use crate::{get_avahi_impl, get_model, get_model_impl, GetModelFn};
+    use crate::{get_pacing, get_serial_settings, parse_args, print_status, Pacing, Receiver};
+    use crate::{get_query_timeout, get_tcp_options, TcpOptions};
+    use crate::{get_receiver_and_port, get_transport, is_heos, main2, main_heos, main_http};
+    use mockall::Sequence;
     use predicates::ord::eq;
     use std::io;
     use std::net::{TcpListener, TcpStream};
     use std::thread;
+    use std::time::Duration;
 
     fn to_string_vec(strings: Vec<&str>) -> Vec<String> {
         strings.into_iter().map(|a| a.to_string()).collect()
@@ -163,7 +650,7 @@ mod test {
     #[should_panic]
     fn parse_args_parnics_with_unknown_option() {
         let logger = MockLogger::new();
-        let string_args = vec!["blub", "-w"];
+        let string_args = vec!["blub", "-Z"];
         parse_args(to_string_vec(string_args), &logger);
     }
 
@@ -189,6 +676,12 @@ mod test {
             "DVD",
             "-e",
             "-s",
+            "-r",
+            "PSBAS 50",
+            "-q",
+            "PS?",
+            "-w",
+            "500",
         ];
         let args = parse_args(to_string_vec(string_args), &logger);
         assert!(matches!(args.opt_str("a"), Some(x) if x == "some_host"));
@@ -198,6 +691,9 @@ mod test {
         assert!(matches!(args.opt_str("i"), Some(x) if x == "DVD"));
         assert!(args.opt_present("e"));
         assert!(args.opt_present("s"));
+        assert_eq!(vec!["PSBAS 50"], args.opt_strs("r"));
+        assert_eq!(vec!["PS?"], args.opt_strs("q"));
+        assert!(matches!(args.opt_get::<u64>("w"), Ok(Some(500))));
     }
 
     #[test]
@@ -215,6 +711,12 @@ mod test {
             "DVD",
             "--extern-avahi",
             "--status",
+            "--raw",
+            "PSBAS 50",
+            "--query",
+            "PS?",
+            "--wait",
+            "500",
         ];
         let args = parse_args(to_string_vec(string_args), &logger);
         assert!(matches!(args.opt_str("a"), Some(x) if x == "some_host"));
@@ -224,15 +726,21 @@ mod test {
         assert!(matches!(args.opt_str("i"), Some(x) if x == "DVD"));
         assert!(args.opt_present("e"));
         assert!(args.opt_present("s"));
+        assert_eq!(vec!["PSBAS 50"], args.opt_strs("r"));
+        assert_eq!(vec!["PS?"], args.opt_strs("q"));
+        assert!(matches!(args.opt_get::<u64>("w"), Ok(Some(500))));
     }
 
     #[test]
     fn print_status_test() -> Result<(), io::Error> {
-        let (mut to_receiver, mut dc) = create_connected_connection()?;
+        let (mut to_receiver, dc) = create_connected_connection()?;
         write_string(&mut to_receiver, "PWON\rSICD\rMV230\rMVMAX666\r")?;
+        let mut receiver = Receiver::new(Box::new(dc));
 
         let expected = "Current status of receiver:\n\tPower(ON)\n\tSourceInput(CD)\n\tMainVolume(230)\n\tMaxVolume(666)\n";
-        assert_eq!(expected, print_status(&mut dc).unwrap());
+        assert_eq!(expected, print_status(&mut receiver, false).unwrap());
+        let expected = "Current status of receiver:\n\tPower(ON) 0s ago\n\tSourceInput(CD) 0s ago\n\tMainVolume(230) 0s ago\n\tMaxVolume(666) 0s ago\n";
+        assert_eq!(expected, print_status(&mut receiver, true).unwrap());
         Ok(())
     }
 
@@ -242,7 +750,10 @@ mod test {
         let string_args = vec!["blub", "--extern-avahi"];
         let args = parse_args(to_string_vec(string_args), &logger);
 
-        assert_eq!(avahi::get_receiver as GetReceiverFn, get_avahi_impl(&args));
+        assert_eq!(
+            avahi::get_receiver as GetReceiverFn as usize,
+            get_avahi_impl(&args) as usize
+        );
     }
 
     #[test]
@@ -251,7 +762,70 @@ mod test {
         let string_args = vec!["blub"];
         let args = parse_args(to_string_vec(string_args), &logger);
 
-        assert_eq!(avahi3::get_receiver as GetReceiverFn, get_avahi_impl(&args));
+        assert_eq!(
+            avahi3::get_receiver as GetReceiverFn as usize,
+            get_avahi_impl(&args) as usize
+        );
+    }
+
+    #[test]
+    fn get_model_impl_test() {
+        let logger = MockLogger::new();
+        let args = parse_args(to_string_vec(vec!["blub", "-e"]), &logger);
+        assert_eq!(
+            avahi::get_model as GetModelFn as usize,
+            get_model_impl(&args) as usize
+        );
+        let args = parse_args(to_string_vec(vec!["blub"]), &logger);
+        assert_eq!(
+            avahi3::get_model as GetModelFn as usize,
+            get_model_impl(&args) as usize
+        );
+    }
+
+    #[test]
+    fn get_model_test() -> Result<(), Error> {
+        let logger = MockLogger::new();
+        let args = parse_args(to_string_vec(vec!["blub"]), &logger);
+        assert_eq!(default_model(), get_model(&args, &logger, |_| panic!())?);
+
+        let args = parse_args(to_string_vec(vec!["blub", "-m", "AVR-X2700H"]), &logger);
+        assert_eq!(
+            find_model("AVR-X"),
+            Some(get_model(&args, &logger, |_| panic!())?)
+        );
+
+        let args = parse_args(to_string_vec(vec!["blub", "--model", "blub"]), &logger);
+        assert!(matches!(
+            get_model(&args, &logger, |_| panic!()),
+            Err(Error::Input(e)) if e == "given model blub is not known"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn get_model_auto_test() -> Result<(), Error> {
+        let mut logger = MockLogger::new();
+        let args = parse_args(to_string_vec(vec!["blub", "-m", "auto"]), &logger);
+        logger
+            .expect_log()
+            .once()
+            .with(eq("detected SR5015, using MARANTZ"))
+            .returning(nothing);
+        logger
+            .expect_log()
+            .once()
+            .with(eq("could not detect model: NoHostsFound, using AVR-1912"))
+            .returning(nothing);
+        assert_eq!(
+            find_model("MARANTZ"),
+            Some(get_model(&args, &logger, |_| Ok(String::from("SR5015")))?)
+        );
+        assert_eq!(
+            default_model(),
+            get_model(&args, &logger, |_| Err(avahi_error::Error::NoHostsFound))?
+        );
+        Ok(())
     }
 
     #[test]
@@ -267,7 +841,7 @@ mod test {
             .returning(nothing);
         assert_eq!(
             (receiver_address, 23),
-            get_receiver_and_port(&args, &mut logger, |_| Ok(String::from("some_receiver")))?
+            get_receiver_and_port(&args, &logger, |_| Ok(String::from("some_receiver")))?
         );
         Ok(())
     }
@@ -302,6 +876,38 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn get_transport_test() -> Result<(), Error> {
+        let logger = MockLogger::new();
+        let args = parse_args(to_string_vec(vec!["blub"]), &logger);
+        assert_eq!(Transport::Telnet, get_transport(&args)?);
+        let args = parse_args(to_string_vec(vec!["blub", "-t", "http"]), &logger);
+        assert_eq!(Transport::Http, get_transport(&args)?);
+        let args = parse_args(to_string_vec(vec!["blub", "--transport", "ftp"]), &logger);
+        assert!(matches!(
+            get_transport(&args),
+            Err(Error::Input(e)) if e == "given transport ftp is not known, use telnet or http"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn get_receiver_and_port_using_http_test() -> Result<(), Error> {
+        let mut logger = MockLogger::new();
+        let string_args = vec!["blub", "-a", "blub_receiver", "-t", "http"];
+        let args = parse_args(to_string_vec(string_args), &logger);
+        logger
+            .expect_log()
+            .once()
+            .with(eq("using receiver: blub_receiver:80"))
+            .returning(nothing);
+        assert_eq!(
+            (String::from("blub_receiver"), 80),
+            get_receiver_and_port(&args, &logger, |_| panic!())?
+        );
+        Ok(())
+    }
+
     #[test]
     fn get_receiver_and_port_using_args_with_port_test() -> Result<(), Error> {
         let mut logger = MockLogger::new();
@@ -342,13 +948,13 @@ mod test {
         let acceptor = thread::spawn(move || -> Result<(TcpStream, Vec<String>), io::Error> {
             let mut to_receiver = listen_socket.accept()?.0;
 
-            let mut received_data = read(&mut to_receiver, 1)?;
+            let mut received_data = read_lines(&to_receiver, 1)?;
             write_string(&mut to_receiver, "PWON\r")?;
-            received_data.append(&mut read(&mut to_receiver, 1)?);
+            received_data.append(&mut read_lines(&to_receiver, 1)?);
             write_string(&mut to_receiver, "SIDVD\r")?;
-            received_data.append(&mut read(&mut to_receiver, 1)?);
+            received_data.append(&mut read_lines(&to_receiver, 1)?);
             write_string(&mut to_receiver, "MV230\r")?;
-            received_data.append(&mut read(&mut to_receiver, 1)?);
+            received_data.append(&mut read_lines(&to_receiver, 1)?);
             write_string(&mut to_receiver, "MVMAX666\r")?;
             Ok((to_receiver, received_data))
         });
@@ -360,7 +966,7 @@ mod test {
             .with(eq(
         "Current status of receiver:\n\tPower(ON)\n\tSourceInput(DVD)\n\tMainVolume(230)\n\tMaxVolume(666)\n"
         )).returning(nothing);
-        assert!(main2(args, s, mlogger).is_ok());
+        assert!(main2(args, s, mlogger, default_model()).is_ok());
 
         let (to_receiver, query_data) = acceptor.join().unwrap()?;
         assert!(query_data.contains(&format!("{}?", State::Power)));
@@ -368,13 +974,287 @@ mod test {
         assert!(query_data.contains(&format!("{}?", State::MainVolume)));
         assert!(query_data.contains(&format!("{}?", State::MaxVolume)));
 
-        let set_data = read(&to_receiver, 3)?;
+        let set_data = read_lines(&to_receiver, 3)?;
         assert!(set_data.contains(&format!("{}", SetState::SourceInput(SourceInputState::Cd))));
         assert!(set_data.contains(&format!("{}", SetState::MainVolume(50))));
         assert!(set_data.contains(&format!("{}", SetState::Power(PowerState::Standby))));
         Ok(())
     }
 
+    #[test]
+    fn main2_raw_and_query_test() -> Result<(), io::Error> {
+        let mut mlogger = Box::new(MockLogger::new());
+        let listen_socket = TcpListener::bind("localhost:0")?;
+        let local_port = listen_socket.local_addr()?.port();
+        let string_args = vec!["blub", "-r", "PSBAS 50", "-q", "PS", "-w", "300"];
+        let args = parse_args(to_string_vec(string_args), &*mlogger);
+
+        let acceptor = thread::spawn(move || -> Result<Vec<String>, io::Error> {
+            let mut to_receiver = listen_socket.accept()?.0;
+
+            let mut received_data = read_lines(&to_receiver, 1)?;
+            write_string(&mut to_receiver, "PSBAS 50\r")?;
+            received_data.append(&mut read_lines(&to_receiver, 1)?);
+            write_string(&mut to_receiver, "PSBAS 50\rPSTRE 48\r")?;
+            Ok(received_data)
+        });
+
+        let s = create_tcp_stream("localhost", local_port)?;
+        let mut sequence = Sequence::new();
+        for expected in ["PSBAS 50", "PSBAS 50", "PSTRE 48"] {
+            mlogger
+                .expect_log()
+                .once()
+                .in_sequence(&mut sequence)
+                .with(eq(expected))
+                .returning(nothing);
+        }
+        assert!(main2(args, s, mlogger, default_model()).is_ok());
+
+        let received_data = acceptor.join().unwrap()?;
+        assert_eq!(vec!["PSBAS 50", "PS?"], received_data);
+        Ok(())
+    }
+
+    #[test]
+    fn main_http_test() -> Result<(), Error> {
+        let mut mlogger = Box::new(MockLogger::new());
+        let string_args = vec!["blub", "-s", "-p", "STANDBY"];
+        let args = parse_args(to_string_vec(string_args), &*mlogger);
+        // the whole status is read from a single page
+        let (server, port) = create_http_stand_in(2, "200 OK", STATUS)?;
+        mlogger
+            .expect_log()
+            .once()
+            .with(eq(
+                "Current status of receiver:\n\tPower(ON)\n\tSourceInput(TV)\n\tMainVolume(395)\n",
+            ))
+            .returning(nothing);
+
+        main_http(args, "localhost", port, mlogger, default_model())?;
+
+        let requests = server.join().unwrap()?;
+        assert_eq!(
+            vec![
+                "GET /goform/formMainZone_MainZoneXml.xml HTTP/1.0",
+                "GET /goform/formiPhoneAppDirect.xml?PWSTANDBY HTTP/1.0"
+            ],
+            requests
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn main_heos_players_test() -> Result<(), Error> {
+        let mut mlogger = Box::new(MockLogger::new());
+        let args = parse_args(to_string_vec(vec!["blub", "heos", "players"]), &*mlogger);
+        assert!(is_heos(&args));
+        let (server, port) = create_fake_heos_server(vec![PLAYERS])?;
+        let mut sequence = Sequence::new();
+        for expected in [
+            "1717\tLiving Room\tDenon AVR-X2700H",
+            "-42\tKitchen\tHEOS 1",
+        ] {
+            mlogger
+                .expect_log()
+                .once()
+                .in_sequence(&mut sequence)
+                .with(eq(expected))
+                .returning(nothing);
+        }
+
+        main_heos(args, create_tcp_stream("localhost", port)?, mlogger)?;
+        assert_eq!(vec!["heos://player/get_players"], server.join().unwrap()?);
+        Ok(())
+    }
+
+    #[test]
+    fn main_heos_uses_given_player_test() -> Result<(), Error> {
+        let mlogger = Box::new(MockLogger::new());
+        let string_args = vec!["blub", "--player", "kitchen", "heos", "volume", "30"];
+        let args = parse_args(to_string_vec(string_args), &*mlogger);
+        let (server, port) = create_fake_heos_server(vec![
+            PLAYERS,
+            "{\"heos\": {\"command\": \"player/set_volume\", \"result\": \"success\", \"message\": \"pid=-42&level=30\"}}\r\n",
+        ])?;
+
+        main_heos(args, create_tcp_stream("localhost", port)?, mlogger)?;
+        assert_eq!(
+            vec![
+                "heos://player/get_players",
+                "heos://player/set_volume?pid=-42&level=30"
+            ],
+            server.join().unwrap()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn main_heos_groups_test() -> Result<(), Error> {
+        let mut mlogger = Box::new(MockLogger::new());
+        let args = parse_args(to_string_vec(vec!["blub", "heos", "groups"]), &*mlogger);
+        let (server, port) = create_fake_heos_server(vec![GROUPS])?;
+        mlogger
+            .expect_log()
+            .once()
+            .with(eq("[{\"gid\":1717,\"name\":\"Living Room + Kitchen\",\"players\":[{\"name\":\"Living Room\",\"pid\":1717,\"role\":\"leader\"},{\"name\":\"Kitchen\",\"pid\":-42,\"role\":\"member\"}]}]"))
+            .returning(nothing);
+
+        main_heos(args, create_tcp_stream("localhost", port)?, mlogger)?;
+        assert_eq!(vec!["heos://group/get_groups"], server.join().unwrap()?);
+        Ok(())
+    }
+
+    #[test]
+    fn main_heos_group_create_and_remove_test() -> Result<(), Error> {
+        let mut mlogger = Box::new(MockLogger::new());
+        let string_args = vec!["blub", "heos", "group", "create", "Living Room", "kitchen"];
+        let args = parse_args(to_string_vec(string_args), &*mlogger);
+        let (server, port) = create_fake_heos_server(vec![
+            PLAYERS,
+            "{\"heos\": {\"command\": \"group/set_group\", \"result\": \"success\", \"message\": \"gid=1717&name=Living Room + Kitchen&pid=1717,-42\"}}\r\n",
+        ])?;
+        mlogger
+            .expect_log()
+            .once()
+            .with(eq("{\"gid\":1717,\"pids\":[1717,-42]}"))
+            .returning(nothing);
+        main_heos(args, create_tcp_stream("localhost", port)?, mlogger)?;
+        assert_eq!(
+            vec![
+                "heos://player/get_players",
+                "heos://group/set_group?pid=1717,-42"
+            ],
+            server.join().unwrap()?
+        );
+
+        let mut mlogger = Box::new(MockLogger::new());
+        let string_args = vec!["blub", "heos", "group", "remove", "1717"];
+        let args = parse_args(to_string_vec(string_args), &*mlogger);
+        let (server, port) = create_fake_heos_server(vec![
+            PLAYERS,
+            "{\"heos\": {\"command\": \"group/set_group\", \"result\": \"success\", \"message\": \"pid=1717\"}}\r\n",
+        ])?;
+        mlogger
+            .expect_log()
+            .once()
+            .with(eq("{\"removed\":1717}"))
+            .returning(nothing);
+        main_heos(args, create_tcp_stream("localhost", port)?, mlogger)?;
+        assert_eq!(
+            vec![
+                "heos://player/get_players",
+                "heos://group/set_group?pid=1717"
+            ],
+            server.join().unwrap()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn main_heos_unknown_player_test() -> Result<(), Error> {
+        let mlogger = Box::new(MockLogger::new());
+        let string_args = vec!["blub", "heos", "group", "create", "1717", "Bathroom"];
+        let args = parse_args(to_string_vec(string_args), &*mlogger);
+        let (server, port) = create_fake_heos_server(vec![PLAYERS])?;
+        assert!(matches!(
+            main_heos(args, create_tcp_stream("localhost", port)?, mlogger),
+            Err(Error::Heos(e)) if e == "no HEOS player Bathroom found"
+        ));
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn main_heos_unknown_action_test() -> Result<(), Error> {
+        let mlogger = Box::new(MockLogger::new());
+        let args = parse_args(to_string_vec(vec!["blub", "heos", "rewind"]), &*mlogger);
+        let (server, port) = create_fake_heos_server(vec![PLAYERS])?;
+
+        assert!(matches!(
+            main_heos(args, create_tcp_stream("localhost", port)?, mlogger),
+            Err(Error::Input(e)) if e.starts_with("unknown heos action rewind, use one of: players")
+        ));
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn get_serial_settings_test() -> Result<(), Error> {
+        let logger = MockLogger::new();
+        let args = parse_args(
+            to_string_vec(vec!["blub", "--serial", "/dev/ttyS0"]),
+            &logger,
+        );
+        assert_eq!(SerialSettings::default(), get_serial_settings(&args)?);
+        assert_eq!(9600, get_serial_settings(&args)?.baud_rate);
+        assert_eq!(Some(String::from("/dev/ttyS0")), args.opt_str("serial"));
+
+        let args = parse_args(to_string_vec(vec!["blub", "--baud", "19200"]), &logger);
+        assert_eq!(19200, get_serial_settings(&args)?.baud_rate);
+        let args = parse_args(to_string_vec(vec!["blub", "--baud", "fast"]), &logger);
+        assert!(matches!(
+            get_serial_settings(&args),
+            Err(Error::ParseInt(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn get_pacing_test() -> Result<(), Error> {
+        let logger = MockLogger::new();
+        let args = parse_args(to_string_vec(vec!["blub"]), &logger);
+        assert_eq!(Pacing::default(), get_pacing(&args)?);
+        let args = parse_args(to_string_vec(vec!["blub", "--pause", "120"]), &logger);
+        assert_eq!(Duration::from_millis(120), get_pacing(&args)?.interval);
+        assert_eq!(
+            Pacing::default().power_on_delay,
+            get_pacing(&args)?.power_on_delay
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn get_tcp_options_and_query_timeout_test() -> Result<(), Error> {
+        let logger = MockLogger::new();
+        let args = parse_args(to_string_vec(vec!["blub"]), &logger);
+        assert_eq!(TcpOptions::default(), get_tcp_options(&args)?);
+        assert_eq!(None, get_query_timeout(&args)?);
+        let string_args = vec![
+            "blub",
+            "--connect-timeout",
+            "1500",
+            "--keepalive",
+            "60",
+            "--read-timeout",
+            "300",
+            "--timeout",
+            "2000",
+        ];
+        let args = parse_args(to_string_vec(string_args), &logger);
+        let options = get_tcp_options(&args)?;
+        assert_eq!(Some(Duration::from_millis(1500)), options.connect_timeout);
+        assert_eq!(Some(Duration::from_secs(60)), options.keepalive);
+        assert_eq!(Some(Duration::from_millis(300)), options.read_timeout);
+        assert_eq!(Some(Duration::from_secs(2)), get_query_timeout(&args)?);
+        Ok(())
+    }
+
+    #[test]
+    fn main2_over_serial_test() -> Result<(), Error> {
+        let mlogger = Box::new(MockLogger::new());
+        let args = parse_args(to_string_vec(vec!["blub", "-p", "ON"]), &*mlogger);
+        let (master, path) = open_pty()?;
+        let s = create_serial_stream(&path, get_serial_settings(&args)?)?;
+
+        main2(args, s, mlogger, default_model())?;
+
+        let mut received = [0; 5];
+        io::Read::read_exact(&mut &master, &mut received)?;
+        assert_eq!(b"PWON\r", &received);
+        Ok(())
+    }
+
     #[test]
     fn main2_less_args_test() -> Result<(), io::Error> {
         let mut mlogger = Box::new(MockLogger::new());
@@ -399,7 +1279,7 @@ mod test {
             .with(eq("got error: ha"))
             .returning(nothing);
 
-        main2(args, msdstream, mlogger).unwrap();
+        main2(args, msdstream, mlogger, default_model()).unwrap();
 
         Ok(())
     }
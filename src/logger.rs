@@ -3,8 +3,9 @@ use std::io::{stdout, Write};
 #[cfg(test)]
 use mockall::automock;
 
+// Send + Sync, as clones of a connection and its threads share the logger
 #[cfg_attr(test, automock)]
-pub trait Logger {
+pub trait Logger: Send + Sync {
     fn log(&self, message: &str);
 }
 
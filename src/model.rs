@@ -0,0 +1,292 @@
+use crate::command::command;
+use crate::state::{get_state, SetState, SourceInputState, State, StateValue};
+
+/// Capabilities of a family of receivers.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Model {
+    pub(crate) name: &'static str,
+    // model names reported by receivers of this family start with one of these
+    pub(crate) model_prefixes: &'static [&'static str],
+    pub(crate) sources: &'static [SourceInputState],
+    pub(crate) states: &'static [State],
+    // protocol names of sources which differ from the default ones
+    pub(crate) source_names: &'static [(SourceInputState, &'static str)],
+}
+
+impl Model {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Model names reported by receivers of this family start with one of these.
+    pub fn model_prefixes(&self) -> &'static [&'static str] {
+        self.model_prefixes
+    }
+
+    pub fn sources(&self) -> &'static [SourceInputState] {
+        self.sources
+    }
+
+    pub fn states(&self) -> &'static [State] {
+        self.states
+    }
+
+    pub fn supports(&self, state: State) -> bool {
+        self.states.contains(&state)
+    }
+
+    pub fn supports_source(&self, source: SourceInputState) -> bool {
+        self.sources.contains(&source)
+    }
+
+    pub fn source_name(&self, source: SourceInputState) -> String {
+        self.source_names
+            .iter()
+            .find(|(s, _)| *s == source)
+            .map(|(_, name)| name.to_string())
+            .unwrap_or_else(|| source.to_string())
+    }
+
+    pub fn decode_source(&self, value: &str) -> Option<SourceInputState> {
+        self.source_names
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(value))
+            .map(|(s, _)| *s)
+            .or_else(|| get_state(SourceInputState::states(), value).ok())
+    }
+
+    pub fn encode(&self, sstate: &SetState) -> String {
+        match *sstate {
+            SetState::SourceInput(si) => format!("{}{}", State::SourceInput, self.source_name(si)),
+            SetState::MainVolume(volume) | SetState::MaxVolume(volume) => {
+                let prefix = command(sstate.convert().0).prefix;
+                // whole steps are sent with two digits, half steps with three
+                if volume % 10 == 0 {
+                    format!("{}{:02}", prefix, volume / 10)
+                } else {
+                    format!("{}{:03}", prefix, volume)
+                }
+            }
+            _ => sstate.to_string(),
+        }
+    }
+
+    /// The value the receiver reports after `sstate` was set, e.g. TV AUDIO
+    /// for the source input sent as TV.
+    pub fn echo(&self, sstate: &SetState) -> StateValue {
+        let (state, value) = sstate.convert();
+        let command = command(state);
+        let encoded = self.encode(sstate);
+        command
+            .parse_received(&encoded[command.prefix.len()..], self)
+            .map(|echo| echo.convert().1)
+            .unwrap_or(value)
+    }
+}
+
+static MODELS: [Model; 3] = [
+    Model {
+        name: "AVR-1912",
+        model_prefixes: &["AVR-1912"],
+        sources: SOURCES_AVR_1912,
+        states: STATES,
+        source_names: &[],
+    },
+    Model {
+        name: "AVR-X",
+        model_prefixes: &["AVR-X", "AVC-X", "AVR-S"],
+        sources: SOURCES_AVR_X,
+        states: STATES,
+        source_names: &[(SourceInputState::TvAudio, "TV")],
+    },
+    Model {
+        name: "MARANTZ",
+        model_prefixes: &["SR", "NR", "AV7", "AV8", "CINEMA"],
+        sources: SOURCES_MARANTZ,
+        states: STATES,
+        source_names: &[(SourceInputState::TvAudio, "TV")],
+    },
+];
+
+static STATES: &[State] = &[
+    State::Power,
+    State::SourceInput,
+    State::MainVolume,
+    State::MaxVolume,
+];
+
+static SOURCES_AVR_1912: &[SourceInputState] = &[
+    SourceInputState::Cd,
+    SourceInputState::Tuner,
+    SourceInputState::Dvd,
+    SourceInputState::Bd,
+    SourceInputState::Tv,
+    SourceInputState::Satcbl,
+    SourceInputState::Game,
+    SourceInputState::Game2,
+    SourceInputState::Vaux,
+    SourceInputState::Dock,
+    SourceInputState::Ipod,
+    SourceInputState::Netusb,
+    SourceInputState::Rhapsody,
+    SourceInputState::Napster,
+    SourceInputState::Pandora,
+    SourceInputState::Lastfm,
+    SourceInputState::Flickr,
+    SourceInputState::Favorites,
+    SourceInputState::Iradio,
+    SourceInputState::Server,
+    SourceInputState::Usbipod,
+    SourceInputState::Usb,
+    SourceInputState::Ipd,
+    SourceInputState::Irp,
+    SourceInputState::Fvp,
+];
+
+static SOURCES_AVR_X: &[SourceInputState] = &[
+    SourceInputState::Cd,
+    SourceInputState::Tuner,
+    SourceInputState::Dvd,
+    SourceInputState::Bd,
+    SourceInputState::TvAudio,
+    SourceInputState::Satcbl,
+    SourceInputState::Mplay,
+    SourceInputState::Game,
+    SourceInputState::Aux1,
+    SourceInputState::Aux2,
+    SourceInputState::Aux3,
+    SourceInputState::Aux4,
+    SourceInputState::Aux5,
+    SourceInputState::Aux6,
+    SourceInputState::Aux7,
+    SourceInputState::Bt,
+    SourceInputState::Heos,
+    SourceInputState::Net,
+    SourceInputState::Phono,
+    SourceInputState::EightK,
+    SourceInputState::Spotify,
+    SourceInputState::Airplay,
+    SourceInputState::Usbipod,
+    SourceInputState::Usb,
+    SourceInputState::Favorites,
+    SourceInputState::Iradio,
+    SourceInputState::Server,
+];
+
+static SOURCES_MARANTZ: &[SourceInputState] = &[
+    SourceInputState::Cd,
+    SourceInputState::Tuner,
+    SourceInputState::Dvd,
+    SourceInputState::Bd,
+    SourceInputState::TvAudio,
+    SourceInputState::Satcbl,
+    SourceInputState::Mplay,
+    SourceInputState::Game,
+    SourceInputState::Aux1,
+    SourceInputState::Aux2,
+    SourceInputState::Bt,
+    SourceInputState::Heos,
+    SourceInputState::Net,
+    SourceInputState::Phono,
+    SourceInputState::Spotify,
+    SourceInputState::Airplay,
+    SourceInputState::Usbipod,
+    SourceInputState::Favorites,
+    SourceInputState::Iradio,
+    SourceInputState::Server,
+];
+
+pub fn models() -> &'static [Model] {
+    &MODELS
+}
+
+pub fn default_model() -> &'static Model {
+    &MODELS[0]
+}
+
+/// Finds the profile by its name or by the name of a model belonging to it.
+pub fn find_model(name: &str) -> Option<&'static Model> {
+    let name = name.trim().to_ascii_uppercase();
+    MODELS.iter().find(|m| m.name == name).or_else(|| {
+        MODELS
+            .iter()
+            .find(|m| m.model_prefixes.iter().any(|p| name.starts_with(p)))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{default_model, find_model, models};
+    use crate::state::{PowerState, SetState, SourceInputState, State, StateValue};
+
+    #[test]
+    fn default_model_is_avr_1912() {
+        let model = default_model();
+        assert_eq!("AVR-1912", model.name);
+        assert_eq!(&SourceInputState::states()[..25], model.sources);
+    }
+
+    #[test]
+    fn find_model_by_profile_name() {
+        assert_eq!(Some(&models()[0]), find_model("AVR-1912"));
+        assert_eq!(Some(&models()[1]), find_model("avr-x"));
+        assert_eq!(Some(&models()[2]), find_model("Marantz"));
+        assert_eq!(None, find_model("blub"));
+    }
+
+    #[test]
+    fn find_model_by_model_name() {
+        assert_eq!("AVR-X", find_model("AVR-X2700H").unwrap().name);
+        assert_eq!("AVR-X", find_model("AVR-S750H").unwrap().name);
+        assert_eq!("MARANTZ", find_model("SR5015").unwrap().name);
+        assert_eq!("MARANTZ", find_model("NR1711").unwrap().name);
+    }
+
+    #[test]
+    fn sources_are_restricted() {
+        let x = find_model("AVR-X").unwrap();
+        assert!(x.supports_source(SourceInputState::Bd));
+        assert!(x.supports_source(SourceInputState::Heos));
+        assert!(!x.supports_source(SourceInputState::Napster));
+        assert!(default_model().supports_source(SourceInputState::Napster));
+        assert!(!default_model().supports_source(SourceInputState::Heos));
+        let marantz = find_model("MARANTZ").unwrap();
+        assert!(marantz.supports_source(SourceInputState::Phono));
+        assert!(!marantz.supports_source(SourceInputState::EightK));
+        assert!(x.supports(State::MaxVolume));
+    }
+
+    #[test]
+    fn encoding_variants() {
+        let x = find_model("AVR-X").unwrap();
+        let tv_audio = SetState::SourceInput(SourceInputState::TvAudio);
+        assert_eq!("SITV", x.encode(&tv_audio));
+        assert_eq!("SITV AUDIO", default_model().encode(&tv_audio));
+        assert_eq!("PWON", x.encode(&SetState::Power(PowerState::On)));
+        assert_eq!("MV45", x.encode(&SetState::MainVolume(450)));
+        assert_eq!("MV455", x.encode(&SetState::MainVolume(455)));
+        assert_eq!("MV05", x.encode(&SetState::MainVolume(50)));
+        assert_eq!("MV005", x.encode(&SetState::MainVolume(5)));
+        assert_eq!("MV095", x.encode(&SetState::MainVolume(95)));
+        assert_eq!("MVMAX98", x.encode(&SetState::MaxVolume(980)));
+        assert_eq!(Some(SourceInputState::TvAudio), x.decode_source("TV"));
+        assert_eq!(Some(SourceInputState::TvAudio), x.decode_source("tv"));
+        assert_eq!(Some(SourceInputState::TvAudio), x.decode_source("TV AUDIO"));
+        assert_eq!(
+            Some(SourceInputState::Tv),
+            default_model().decode_source("TV")
+        );
+        assert_eq!(None, default_model().decode_source("BLUB"));
+    }
+
+    #[test]
+    fn echo_is_normalised() {
+        let x = find_model("AVR-X").unwrap();
+        assert_eq!(StateValue::Integer(450), x.echo(&SetState::MainVolume(450)));
+        assert_eq!(StateValue::Integer(455), x.echo(&SetState::MainVolume(455)));
+        assert_eq!(
+            StateValue::SourceInput(SourceInputState::TvAudio),
+            x.echo(&SetState::SourceInput(SourceInputState::TvAudio))
+        );
+    }
+}
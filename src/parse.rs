@@ -1,121 +1,155 @@
-use crate::state::get_state;
+use crate::command::find_command;
+use crate::model::Model;
 use crate::state::SetState;
-use crate::state::{PowerState, SourceInputState, State};
-
-macro_rules! parsehelper {
-    ($trimmed:expr, $op:expr, $ss:expr, $func:path) => {
-        if $trimmed.starts_with($op.to_string().as_str()) {
-            let value = get_value($trimmed, &$op);
-            let x = $func(value);
-            return Some($ss(x));
-        }
-    };
-}
+use std::fmt::{Display, Formatter};
 
-fn get_value<'a>(trimmed: &'a str, op: &State) -> &'a str {
-    let to_skip = op.to_string().len();
-    trimmed[to_skip..].trim()
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: String,
+    pub token: String,
+    pub expected: &'static str,
 }
 
-fn parse_int(to_parse: &str) -> u32 {
-    let mut value = to_parse.parse::<u32>().unwrap();
-    if value < 100 {
-        value *= 10;
+impl Display for ParseError {
+    fn fmt(&self, format: &mut Formatter) -> Result<(), std::fmt::Error> {
+        write!(
+            format,
+            "invalid value '{}' in line '{}', expected {}",
+            self.token, self.line, self.expected
+        )
     }
-    value
-}
-
-fn parse_power(value: &str) -> PowerState {
-    let ps = get_state(PowerState::states(), value);
-    ps.unwrap_or(PowerState::Standby)
 }
 
-fn parse_source_input(value: &str) -> SourceInputState {
-    let sis = get_state(SourceInputState::states(), value);
-    sis.unwrap_or(SourceInputState::Unknown)
-}
-
-pub fn parse(str: &str) -> Option<SetState> {
+/// Parses one line sent by the receiver. Lines of commands which are not
+/// modelled yield `Ok(None)`, known commands with malformed values an error.
+pub fn parse(str: &str, model: &Model) -> Result<Option<SetState>, ParseError> {
     let trimmed = str.trim().trim_matches('\r');
-    parsehelper!(trimmed, State::MaxVolume, SetState::MaxVolume, parse_int);
-    parsehelper!(trimmed, State::MainVolume, SetState::MainVolume, parse_int);
-    parsehelper!(trimmed, State::Power, SetState::Power, parse_power);
-    parsehelper!(
-        trimmed,
-        State::SourceInput,
-        SetState::SourceInput,
-        parse_source_input
-    );
-    None
+    let command = match find_command(trimmed) {
+        Some(command) => command,
+        None => return Ok(None),
+    };
+    let value = trimmed[command.prefix.len()..].trim();
+    command
+        .parse_received(value, model)
+        .map(Some)
+        .map_err(|expected| ParseError {
+            line: trimmed.to_owned(),
+            token: value.to_owned(),
+            expected,
+        })
 }
 
 #[cfg(test)]
 mod test {
-    use super::parse;
-    use crate::{
-        parse::{PowerState, SourceInputState},
-        state::SetState,
-    };
+    use super::{parse, ParseError};
+    use crate::model::{default_model, find_model};
+    use crate::state::{PowerState, SetState, SourceInputState};
 
     #[test]
     fn parse_with_unknown_string() {
-        assert_eq!(None, parse(""));
-        assert_eq!(None, parse("blub"));
+        assert_eq!(Ok(None), parse("", default_model()));
+        assert_eq!(Ok(None), parse("blub", default_model()));
     }
 
     #[test]
-    #[should_panic]
-    fn max_volume_without_value_panics() {
-        parse("MVMAX");
+    fn max_volume_without_value_fails() {
+        let expected = ParseError {
+            line: String::from("MVMAX"),
+            token: String::new(),
+            expected: "a volume of two or three digits",
+        };
+        assert_eq!(parse("MVMAX", default_model()), Err(expected));
     }
 
     #[test]
     fn max_volume() {
-        let create = |i| Some(SetState::MaxVolume(i));
-
-        assert_eq!(parse("MVMAX0"), create(0));
-        assert_eq!(parse("MVMAX23"), create(230));
-        assert_eq!(parse("MVMAX99"), create(990));
-        assert_eq!(parse("MVMAX100"), create(100));
-        assert_eq!(parse("MVMAX230"), create(230));
-        assert_eq!(parse("MVMAX999"), create(999));
-        assert_eq!(parse("MVMAX 999"), create(999));
+        let create = |i| Ok(Some(SetState::MaxVolume(i)));
+
+        assert_eq!(parse("MVMAX0", default_model()), create(0));
+        assert_eq!(parse("MVMAX23", default_model()), create(230));
+        assert_eq!(parse("MVMAX99", default_model()), create(990));
+        assert_eq!(parse("MVMAX100", default_model()), create(100));
+        assert_eq!(parse("MVMAX230", default_model()), create(230));
+        assert_eq!(parse("MVMAX999", default_model()), create(999));
+        assert_eq!(parse("MVMAX 999", default_model()), create(999));
+    }
+
+    #[test]
+    fn main_volume_without_value_fails() {
+        let error = parse("MV", default_model()).unwrap_err();
+        assert_eq!("MV", error.line);
+        assert_eq!("", error.token);
     }
 
     #[test]
-    #[should_panic]
-    fn main_volume_without_value_panics() {
-        parse("MV");
+    fn main_volume_with_garbage_fails() {
+        let error = parse("MV 4x\r", default_model()).unwrap_err();
+        assert_eq!(
+            "invalid value '4x' in line 'MV 4x', expected a volume of two or three digits",
+            error.to_string()
+        );
     }
 
     #[test]
     fn main_volume() {
-        let create = |i| Some(SetState::MainVolume(i));
-
-        assert_eq!(parse("MV 0"), create(0));
-        assert_eq!(parse("MV 23"), create(230));
-        assert_eq!(parse("MV 99"), create(990));
-        assert_eq!(parse("MV 100"), create(100));
-        assert_eq!(parse("MV 230"), create(230));
-        assert_eq!(parse("MV 999"), create(999));
-        assert_eq!(parse("MV999"), create(999));
+        let create = |i| Ok(Some(SetState::MainVolume(i)));
+
+        assert_eq!(parse("MV 0", default_model()), create(0));
+        assert_eq!(parse("MV 23", default_model()), create(230));
+        assert_eq!(parse("MV 99", default_model()), create(990));
+        assert_eq!(parse("MV 100", default_model()), create(100));
+        assert_eq!(parse("MV 230", default_model()), create(230));
+        assert_eq!(parse("MV 999", default_model()), create(999));
+        assert_eq!(parse("MV999", default_model()), create(999));
     }
 
     #[test]
     fn power() {
-        let create = |ps| Some(SetState::Power(ps));
+        let create = |ps| Ok(Some(SetState::Power(ps)));
 
-        assert_eq!(parse("PW"), create(PowerState::Standby));
-        assert_eq!(parse("PWOFF"), create(PowerState::Standby));
-        assert_eq!(parse("PWON"), create(PowerState::On));
+        assert_eq!(parse("PW", default_model()), create(PowerState::Standby));
+        assert_eq!(parse("PWOFF", default_model()), create(PowerState::Standby));
+        assert_eq!(parse("PWON", default_model()), create(PowerState::On));
     }
 
     #[test]
     fn source_input() {
-        let create = |si| Some(SetState::SourceInput(si));
+        let create = |si| Ok(Some(SetState::SourceInput(si)));
+
+        assert_eq!(
+            parse("SI", default_model()),
+            create(SourceInputState::Unknown)
+        );
+        assert_eq!(
+            parse("SIblub", default_model()),
+            create(SourceInputState::Unknown)
+        );
+        assert_eq!(parse("SITV", default_model()), create(SourceInputState::Tv));
+        assert_eq!(
+            parse("SIBLUB", default_model()),
+            create(SourceInputState::Unknown)
+        );
+        assert_eq!(
+            parse("SITV AUDIO", default_model()),
+            create(SourceInputState::TvAudio)
+        );
+        assert_eq!(
+            parse("SI8K", default_model()),
+            create(SourceInputState::EightK)
+        );
+        assert_eq!(
+            parse("SISAT/CBL", default_model()),
+            create(SourceInputState::Satcbl)
+        );
+    }
+
+    #[test]
+    fn source_input_of_model() {
+        let create = |si| Ok(Some(SetState::SourceInput(si)));
+        let x = find_model("AVR-X").unwrap();
 
-        assert_eq!(parse("SI"), create(SourceInputState::Unknown));
-        assert_eq!(parse("SIblub"), create(SourceInputState::Unknown));
-        assert_eq!(parse("SITV"), create(SourceInputState::Tv));
+        assert_eq!(parse("SINET", x), create(SourceInputState::Net));
+        assert_eq!(parse("SITV", x), create(SourceInputState::TvAudio));
+        assert_eq!(parse("SIAUX3", x), create(SourceInputState::Aux3));
     }
 }
@@ -0,0 +1,324 @@
+use crate::builder::ConnectionBuilder;
+use crate::command::{commands, Zone, MAIN_VOLUME_CAP};
+use crate::connection::Connection;
+use crate::denon_connection::{Change, ConnectionEvent, Health, Received};
+use crate::error::Error;
+use crate::logger::Logger;
+use crate::model::Model;
+use crate::state::{PowerState, SetState, SourceInputState, State, StateValue};
+use std::io;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+/// A receiver controlled over any transport. This is the API for embedding
+/// the crate, the command line tool is built on it as well. `ConnectionBuilder`
+/// configures the connection in more detail. Clones share the connection,
+/// so each thread may use its own clone.
+pub struct Receiver {
+    connection: Box<dyn Connection>,
+}
+
+impl Clone for Receiver {
+    fn clone(&self) -> Self {
+        Receiver::new(self.connection.clone_box())
+    }
+}
+
+impl Receiver {
+    pub(crate) fn new(connection: Box<dyn Connection>) -> Receiver {
+        Receiver { connection }
+    }
+
+    /// Connects with telnet, which only one client may use at a time.
+    pub fn telnet(
+        denon_name: &str,
+        denon_port: u16,
+        logger: Arc<dyn Logger>,
+        model: &'static Model,
+    ) -> Result<Receiver, Error> {
+        ConnectionBuilder::new(model)
+            .logger(logger)
+            .connect(denon_name, denon_port)
+    }
+
+    /// Uses the web interface, which may be shared with other clients.
+    pub fn http(
+        denon_name: &str,
+        denon_port: u16,
+        logger: Arc<dyn Logger>,
+        model: &'static Model,
+    ) -> Receiver {
+        ConnectionBuilder::new(model)
+            .logger(logger)
+            .http(denon_name, denon_port)
+    }
+
+    pub fn power(&mut self) -> Result<PowerState, Error> {
+        match self.get(State::Power)? {
+            StateValue::Power(power) => Ok(power),
+            value => Err(Error::UnexpectedValue(State::Power, value)),
+        }
+    }
+
+    pub fn set_power(&mut self, power: PowerState) -> Result<(), Error> {
+        self.set(SetState::Power(power))
+    }
+
+    pub fn input(&mut self) -> Result<SourceInputState, Error> {
+        match self.get(State::SourceInput)? {
+            StateValue::SourceInput(input) => Ok(input),
+            value => Err(Error::UnexpectedValue(State::SourceInput, value)),
+        }
+    }
+
+    pub fn set_input(&mut self, input: SourceInputState) -> Result<(), Error> {
+        self.set(SetState::SourceInput(input))
+    }
+
+    /// The volume in tenths of a step, e.g. 455 is 45.5 and 450 is 45.
+    pub fn volume(&mut self) -> Result<u32, Error> {
+        self.integer(State::MainVolume)
+    }
+
+    /// Sets the volume in tenths of a step like `volume()` returns it. The
+    /// receiver only knows half steps, so e.g. 457 is set as 455, and values
+    /// above the cap of `--volume` are set as the cap.
+    pub fn set_volume(&mut self, volume: u32) -> Result<(), Error> {
+        let volume = volume.min(MAIN_VOLUME_CAP * 10);
+        self.set(SetState::MainVolume(volume - volume % 5))
+    }
+
+    pub fn max_volume(&mut self) -> Result<u32, Error> {
+        self.integer(State::MaxVolume)
+    }
+
+    /// Whether the value of `state` can be read, which depends on the model
+    /// and the transport.
+    pub fn supports(&self, state: State) -> bool {
+        self.connection.supports(state)
+    }
+
+    /// Zones, which have at least one command of the receiver.
+    pub fn zones(&self) -> Vec<Zone> {
+        let mut zones: Vec<Zone> = Vec::new();
+        for command in commands() {
+            if !self.model().supports(command.state) {
+                continue;
+            }
+            for zone in command.zones {
+                if !zones.contains(zone) {
+                    zones.push(*zone);
+                }
+            }
+        }
+        zones
+    }
+
+    pub fn get(&mut self, state: State) -> Result<StateValue, Error> {
+        self.connection.get(state)
+    }
+
+    /// How long `get()` waits for the answer of the receiver.
+    pub fn query_timeout(&self) -> Duration {
+        self.connection.query_timeout()
+    }
+
+    /// Like `get()`, but queries the receiver if the value is older than
+    /// `max_age`.
+    pub fn get_fresh(&mut self, state: State, max_age: Duration) -> Result<StateValue, Error> {
+        self.connection.get_fresh(state, max_age)
+    }
+
+    /// Forgets all received states and queries those supported again, e.g.
+    /// after the receiver was operated by its remote control.
+    pub fn refresh_all(&mut self) -> Result<Vec<StateValue>, Error> {
+        self.connection.refresh_all()
+    }
+
+    /// Values older than `max_age` are queried again, `None` keeps them
+    /// until the receiver reports a change.
+    pub fn set_max_age(&mut self, max_age: Option<Duration>) {
+        self.connection.set_max_age(max_age)
+    }
+
+    /// Returns the values of all `states`, which may be fetched at once.
+    pub fn get_many(&mut self, states: &[State]) -> Result<Vec<StateValue>, Error> {
+        self.connection.query_many(states)
+    }
+
+    pub fn set(&mut self, sstate: SetState) -> Result<(), Error> {
+        Ok(self.connection.set(sstate)?)
+    }
+
+    /// Sets `sstate` and waits at most `timeout` until the receiver reports it.
+    pub fn set_confirmed(
+        &mut self,
+        sstate: SetState,
+        timeout: Duration,
+    ) -> Result<StateValue, Error> {
+        self.connection.set_confirmed(sstate, timeout)
+    }
+
+    /// Sends `command` unmodified and returns the replies of the receiver.
+    pub fn send_raw(&mut self, command: &str, wait: Duration) -> Result<Vec<String>, Error> {
+        Ok(self.connection.send_raw(command, wait)?)
+    }
+
+    /// How long ago the value of `state` was received, if it is cached.
+    pub fn age(&self, state: State) -> Option<Duration> {
+        self.connection.age(state)
+    }
+
+    /// Returns the last received value of `state` with its age and origin.
+    pub fn received(&self, state: State) -> Option<Received> {
+        self.connection.received(state)
+    }
+
+    pub fn model(&self) -> &'static Model {
+        self.connection.model()
+    }
+
+    /// Number of values received from the receiver which could not be parsed.
+    pub fn parse_errors(&self) -> usize {
+        self.connection.parse_errors()
+    }
+
+    /// Returns a channel, which receives a `Change` whenever the receiver
+    /// reports a new value of any state. Only telnet connections report
+    /// changes.
+    pub fn subscribe(&self) -> Result<mpsc::Receiver<Change>, Error> {
+        Ok(self.connection.subscribe(None)?)
+    }
+
+    /// Like `subscribe()`, but only for the changes of `states`.
+    pub fn subscribe_to(&self, states: &[State]) -> Result<mpsc::Receiver<Change>, Error> {
+        Ok(self.connection.subscribe(Some(states))?)
+    }
+
+    /// Returns a channel, which receives an event whenever the connection
+    /// is lost or established again.
+    pub fn events(&self) -> Result<mpsc::Receiver<ConnectionEvent>, Error> {
+        Ok(self.connection.events()?)
+    }
+
+    /// Whether the receiver is connected, or why the connection was lost.
+    pub fn health(&self) -> Health {
+        self.connection.health()
+    }
+
+    /// Whether the connection is still usable, even if it is reconnecting.
+    pub fn is_alive(&self) -> bool {
+        !matches!(self.health(), Health::Lost(_))
+    }
+
+    /// Shuts the connection down.
+    pub fn stop(&mut self) -> Result<(), io::Error> {
+        self.connection.stop()
+    }
+
+    fn integer(&mut self, state: State) -> Result<u32, Error> {
+        match self.get(state)? {
+            StateValue::Integer(i) => Ok(i),
+            value => Err(Error::UnexpectedValue(state, value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Receiver;
+    use crate::command::Zone;
+    use crate::denon_connection::test::{create_connected_connection, read_lines};
+    use crate::denon_connection::write_string;
+    use crate::error::Error;
+    use crate::http_connection::test::{create_http_stand_in, STATUS};
+    use crate::logger::StdoutLogger;
+    use crate::model::default_model;
+    use crate::state::{PowerState, SourceInputState, State, StateValue};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn typed_getters_and_setters() -> Result<(), Error> {
+        let (mut to_receiver, dc) = create_connected_connection()?;
+        write_string(&mut to_receiver, "PWON\rSICD\rMV455\rMVMAX666\r")?;
+        while dc.received(State::MaxVolume).is_none() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut receiver = Receiver::new(Box::new(dc));
+
+        assert_eq!(PowerState::On, receiver.power()?);
+        assert_eq!(SourceInputState::Cd, receiver.input()?);
+        assert_eq!(455, receiver.volume()?);
+        assert_eq!(666, receiver.max_volume()?);
+        assert_eq!(vec![Zone::Main], receiver.zones());
+
+        receiver.set_power(PowerState::Standby)?;
+        receiver.set_input(SourceInputState::Tuner)?;
+        receiver.set_volume(230)?;
+        assert_eq!(
+            vec!["PWSTANDBY", "SITUNER", "MV23"],
+            read_lines(&to_receiver, 3)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn volume_is_read_back_as_set() -> Result<(), Error> {
+        for (volume, sent, read_back) in [
+            (450, "MV45", 450),
+            (457, "MV455", 455),
+            (5, "MV005", 5),
+            (15, "MV015", 15),
+            (50, "MV05", 50),
+            (95, "MV095", 95),
+            (100, "MV10", 100),
+            (600, "MV50", 500),
+        ] {
+            let (mut to_receiver, dc) = create_connected_connection()?;
+            let mut receiver = Receiver::new(Box::new(dc));
+            receiver.set_volume(volume)?;
+            assert_eq!(vec![sent], read_lines(&to_receiver, 1)?);
+            write_string(&mut to_receiver, &format!("{}\r", sent))?;
+            while receiver.age(State::MainVolume).is_none() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            assert_eq!(read_back, receiver.volume()?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_value_is_an_error() -> Result<(), Error> {
+        // the status page has no maximum volume, so it is not even requested
+        let (server, port) = create_http_stand_in(0, "200 OK", STATUS)?;
+        let logger = Arc::new(StdoutLogger::default());
+        let mut receiver = Receiver::http("localhost", port, logger, default_model());
+        assert!(matches!(
+            receiver.max_volume(),
+            Err(Error::UnexpectedValue(
+                State::MaxVolume,
+                StateValue::Unknown
+            ))
+        ));
+        server.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn clones_share_the_connection_between_threads() -> Result<(), Error> {
+        let (mut to_receiver, dc) = create_connected_connection()?;
+        write_string(&mut to_receiver, "PWON\r")?;
+        let receiver = Receiver::new(Box::new(dc));
+        let mut clone = receiver.clone();
+        let getter = thread::spawn(move || clone.power().ok());
+        assert_eq!(Some(PowerState::On), getter.join().unwrap());
+
+        fn shareable<T: Clone + Send + Sync>(_: &T) {}
+        shareable(&receiver);
+        receiver.clone().set_power(PowerState::Standby)?;
+        assert_eq!(vec!["PWSTANDBY"], read_lines(&to_receiver, 1)?);
+        Ok(())
+    }
+}
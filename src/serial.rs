@@ -0,0 +1,322 @@
+use crate::stream::{ConnectionStream, ReadStream};
+use std::cmp::min;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialSettings {
+    pub baud_rate: u32,
+    // how long a read waits for data before it gives the reader thread a
+    // chance to notice a shutdown
+    pub timeout: Duration,
+}
+
+impl Default for SerialSettings {
+    fn default() -> Self {
+        SerialSettings {
+            baud_rate: 9600,
+            timeout: Duration::from_millis(100),
+        }
+    }
+}
+
+fn speed(baud_rate: u32) -> Result<libc::speed_t, io::Error> {
+    match baud_rate {
+        1200 => Ok(libc::B1200),
+        2400 => Ok(libc::B2400),
+        4800 => Ok(libc::B4800),
+        9600 => Ok(libc::B9600),
+        19200 => Ok(libc::B19200),
+        38400 => Ok(libc::B38400),
+        57600 => Ok(libc::B57600),
+        115200 => Ok(libc::B115200),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("unsupported baud rate {}", baud_rate),
+        )),
+    }
+}
+
+fn check(rc: libc::c_int) -> Result<(), io::Error> {
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// raw mode with 8 data bits, no parity, one stop bit and no flow control
+fn configure(file: &File, settings: SerialSettings) -> Result<(), io::Error> {
+    let speed = speed(settings.baud_rate)?;
+    let fd = file.as_raw_fd();
+    // SAFETY: termios is plain data, which tcgetattr overwrites
+    let mut tty: libc::termios = unsafe { std::mem::zeroed() };
+    unsafe {
+        check(libc::tcgetattr(fd, &mut tty))?;
+        libc::cfmakeraw(&mut tty);
+    }
+    tty.c_cflag &= !(libc::PARENB | libc::CSTOPB | libc::CSIZE | libc::CRTSCTS);
+    tty.c_cflag |= libc::CS8 | libc::CLOCAL | libc::CREAD;
+    tty.c_iflag &= !(libc::IXON | libc::IXOFF | libc::IXANY);
+    unsafe {
+        check(libc::cfsetispeed(&mut tty, speed))?;
+        check(libc::cfsetospeed(&mut tty, speed))?;
+        check(libc::tcsetattr(fd, libc::TCSANOW, &tty))
+    }
+}
+
+pub struct SerialStream {
+    port: File,
+    timeout: Duration,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl Write for SerialStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.port.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
+impl ConnectionStream for SerialStream {
+    // a serial port cannot be shut down, so the reader stops at its next timeout
+    fn shutdownly(&self) -> io::Result<()> {
+        self.shutdown.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn get_readstream(&self) -> io::Result<Box<dyn ReadStream>> {
+        Ok(Box::new(SerialReadStream {
+            port: self.port.try_clone()?,
+            timeout: self.timeout,
+            shutdown: self.shutdown.clone(),
+            buffer: Mutex::default(),
+        }))
+    }
+}
+
+// serial ports cannot peek, so read data is kept until it is consumed
+struct SerialReadStream {
+    port: File,
+    timeout: Duration,
+    shutdown: Arc<AtomicBool>,
+    buffer: Mutex<Vec<u8>>,
+}
+
+impl SerialReadStream {
+    /// Appends the available data to `buffer`, waiting at most `timeout`.
+    fn fill(&self, buffer: &mut Vec<u8>, timeout: Duration) -> Result<(), io::Error> {
+        let mut fds = libc::pollfd {
+            fd: self.port.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().try_into().unwrap_or(libc::c_int::MAX);
+        // SAFETY: fds is valid for the duration of the call
+        let rc = unsafe { libc::poll(&mut fds, 1, timeout) };
+        if rc < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                ErrorKind::Interrupted => Ok(()),
+                _ => Err(e),
+            };
+        }
+        if rc > 0 {
+            let mut chunk = [0; 256];
+            let read_bytes = (&self.port).read(&mut chunk)?;
+            buffer.extend_from_slice(&chunk[..read_bytes]);
+        }
+        Ok(())
+    }
+}
+
+impl ReadStream for SerialReadStream {
+    fn peekly(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buffer = self.buffer.lock().unwrap();
+        // only wait if no complete line is buffered
+        let timeout = if buffer.contains(&b'\r') {
+            Duration::ZERO
+        } else {
+            self.timeout
+        };
+        self.fill(&mut buffer, timeout)?;
+        if self.shutdown.load(Ordering::Relaxed) {
+            return Ok(0);
+        }
+        if buffer.is_empty() {
+            return Err(io::Error::from(ErrorKind::TimedOut));
+        }
+        let length = min(buf.len(), buffer.len());
+        buf[..length].copy_from_slice(&buffer[..length]);
+        Ok(length)
+    }
+
+    fn read_exactly(&self, buf: &mut [u8]) -> io::Result<()> {
+        let mut buffer = self.buffer.lock().unwrap();
+        while buffer.len() < buf.len() {
+            if self.shutdown.load(Ordering::Relaxed) {
+                return Err(io::Error::from(ErrorKind::UnexpectedEof));
+            }
+            self.fill(&mut buffer, self.timeout)?;
+        }
+        buf.copy_from_slice(&buffer[..buf.len()]);
+        buffer.drain(..buf.len());
+        Ok(())
+    }
+}
+
+pub fn create_serial_stream(
+    device: &str,
+    settings: SerialSettings,
+) -> Result<Box<dyn ConnectionStream>, io::Error> {
+    let port = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(device)?;
+    configure(&port, settings)?;
+    Ok(Box::new(SerialStream {
+        port,
+        timeout: settings.timeout,
+        shutdown: Arc::new(AtomicBool::new(false)),
+    }))
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{create_serial_stream, SerialSettings};
+    use crate::codec::LineCodec;
+    use crate::denon_connection::{test::fill_lines, DenonConnection};
+    use crate::error::Error;
+    use crate::logger::MockLogger;
+    use crate::model::default_model;
+    use crate::state::{PowerState, SetState, State, StateValue};
+    use std::ffi::CStr;
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, ErrorKind, Read, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Opens a pseudo-terminal pair and returns the controlling side and
+    /// the path of the device side.
+    pub fn open_pty() -> Result<(File, String), io::Error> {
+        // ptsname uses a static buffer
+        static PTSNAME: Mutex<()> = Mutex::new(());
+        let _guard = PTSNAME.lock().unwrap();
+        // SAFETY: the returned descriptor is owned by the File
+        unsafe {
+            let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if master < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let master_file = File::from_raw_fd(master);
+            if libc::grantpt(master) != 0 || libc::unlockpt(master) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let name = libc::ptsname(master);
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            let path = CStr::from_ptr(name).to_string_lossy().into_owned();
+            Ok((master_file, path))
+        }
+    }
+
+    fn read_until_carriage_return(master: &mut File) -> Result<String, io::Error> {
+        let mut received = Vec::new();
+        let mut byte = [0; 1];
+        while !received.ends_with(b"\r") {
+            master.read_exact(&mut byte)?;
+            received.push(byte[0]);
+        }
+        Ok(String::from_utf8_lossy(&received).to_string())
+    }
+
+    #[test]
+    fn configures_line_settings() -> Result<(), io::Error> {
+        let (_master, path) = open_pty()?;
+        let _stream = create_serial_stream(&path, SerialSettings::default())?;
+
+        let device = OpenOptions::new().read(true).open(&path)?;
+        // SAFETY: termios is plain data, which tcgetattr overwrites
+        let mut tty: libc::termios = unsafe { std::mem::zeroed() };
+        assert_eq!(0, unsafe { libc::tcgetattr(device.as_raw_fd(), &mut tty) });
+        assert_eq!(libc::B9600, unsafe { libc::cfgetospeed(&tty) });
+        assert_eq!(libc::CS8, tty.c_cflag & libc::CSIZE);
+        assert_eq!(0, tty.c_cflag & (libc::PARENB | libc::CSTOPB));
+        assert_eq!(0, tty.c_lflag & libc::ICANON);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_unsupported_baud_rate() -> Result<(), io::Error> {
+        let (_master, path) = open_pty()?;
+        let settings = SerialSettings {
+            baud_rate: 1234,
+            ..SerialSettings::default()
+        };
+        let error = create_serial_stream(&path, settings).err().unwrap();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        assert_eq!("unsupported baud rate 1234", error.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn reads_lines_split_across_writes() -> Result<(), io::Error> {
+        let (mut master, path) = open_pty()?;
+        let stream = create_serial_stream(&path, SerialSettings::default())?;
+        let read_stream = stream.get_readstream()?;
+        let mut codec = LineCodec::default();
+
+        assert_eq!(
+            ErrorKind::TimedOut,
+            codec.fill(read_stream.as_ref()).unwrap_err().kind()
+        );
+        master.write_all(b"PW")?;
+        let writer = thread::spawn(move || -> Result<File, io::Error> {
+            thread::sleep(Duration::from_millis(50));
+            master.write_all(b"ON\rMV50\r")?;
+            Ok(master)
+        });
+        // reading times out until the rest of the line arrives
+        let lines = loop {
+            match fill_lines(&mut codec, read_stream.as_ref(), 2) {
+                Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+                lines => break lines?,
+            }
+        };
+        assert_eq!(vec!["PWON", "MV50"], lines);
+
+        stream.shutdownly()?;
+        assert_eq!(0, codec.fill(read_stream.as_ref())?);
+        writer.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn connection_works_over_serial() -> Result<(), Error> {
+        let (mut master, path) = open_pty()?;
+        let stream = create_serial_stream(&path, SerialSettings::default())?;
+        let mut dc = DenonConnection::new(stream, Arc::new(MockLogger::new()), default_model())?;
+
+        dc.set(SetState::Power(PowerState::On))?;
+        assert_eq!("PWON\r", read_until_carriage_return(&mut master)?);
+
+        master.write_all(b"MV50\r")?;
+        assert_eq!(
+            StateValue::Integer(500),
+            dc.get_timeout(State::MainVolume, Duration::from_secs(5))?
+        );
+        drop(dc);
+        Ok(())
+    }
+}
@@ -1,6 +1,8 @@
+use crate::command::command;
+pub use crate::command::{SetState, State};
 use std::cmp::{Eq, PartialEq};
 use std::fmt::{Display, Error, Formatter, Write};
-use std::hash::Hash;
+use std::str::FromStr;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PowerState {
@@ -23,7 +25,24 @@ impl PowerState {
     }
 }
 
+impl Named for PowerState {
+    const DESCRIPTION: &'static str = "power state";
+
+    fn variants() -> &'static [Self] {
+        PowerState::states()
+    }
+}
+
+impl FromStr for PowerState {
+    type Err = crate::error::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        from_name(value)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum SourceInputState {
     Cd,
     Tuner,
@@ -50,6 +69,22 @@ pub enum SourceInputState {
     Ipd,
     Irp,
     Fvp,
+    Mplay,
+    Aux1,
+    Aux2,
+    Aux3,
+    Aux4,
+    Aux5,
+    Aux6,
+    Aux7,
+    Bt,
+    Heos,
+    Net,
+    Phono,
+    EightK,
+    Spotify,
+    Airplay,
+    TvAudio,
     Unknown,
 }
 
@@ -57,8 +92,11 @@ impl Display for SourceInputState {
     fn fmt(&self, format: &mut Formatter) -> Result<(), Error> {
         let mut buffer = String::new();
         match *self {
+            SourceInputState::Satcbl => write!(&mut buffer, "SAT/CBL")?,
             SourceInputState::Netusb => write!(&mut buffer, "NET/USB")?,
             SourceInputState::Usbipod => write!(&mut buffer, "USB/IPOD")?,
+            SourceInputState::EightK => write!(&mut buffer, "8K")?,
+            SourceInputState::TvAudio => write!(&mut buffer, "TV AUDIO")?,
             _ => write!(&mut buffer, "{:?}", self)?,
         }
         write!(format, "{}", buffer.to_ascii_uppercase())
@@ -67,7 +105,7 @@ impl Display for SourceInputState {
 
 impl SourceInputState {
     pub fn states() -> &'static [SourceInputState] {
-        static STATES: [SourceInputState; 25] = [
+        static STATES: [SourceInputState; 41] = [
             SourceInputState::Cd,
             SourceInputState::Tuner,
             SourceInputState::Dvd,
@@ -93,48 +131,58 @@ impl SourceInputState {
             SourceInputState::Ipd,
             SourceInputState::Irp,
             SourceInputState::Fvp,
+            SourceInputState::Mplay,
+            SourceInputState::Aux1,
+            SourceInputState::Aux2,
+            SourceInputState::Aux3,
+            SourceInputState::Aux4,
+            SourceInputState::Aux5,
+            SourceInputState::Aux6,
+            SourceInputState::Aux7,
+            SourceInputState::Bt,
+            SourceInputState::Heos,
+            SourceInputState::Net,
+            SourceInputState::Phono,
+            SourceInputState::EightK,
+            SourceInputState::Spotify,
+            SourceInputState::Airplay,
+            SourceInputState::TvAudio,
         ];
         &STATES
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub enum State {
-    Power,
-    SourceInput,
-    MaxVolume,
-    MainVolume,
-}
+impl Named for SourceInputState {
+    const DESCRIPTION: &'static str = "source input";
 
-impl Display for State {
-    fn fmt(&self, format: &mut Formatter) -> Result<(), Error> {
-        let val = match *self {
-            State::Power => "PW",
-            State::SourceInput => "SI",
-            State::MaxVolume => "MVMAX",
-            State::MainVolume => "MV",
-        };
-        write!(format, "{}", val)
+    fn variants() -> &'static [Self] {
+        SourceInputState::states()
+    }
+
+    fn aliases() -> &'static [(&'static str, Self)] {
+        &[
+            ("BLURAY", SourceInputState::Bd),
+            ("SAT", SourceInputState::Satcbl),
+            ("CBL", SourceInputState::Satcbl),
+            ("CABLE", SourceInputState::Satcbl),
+            ("BLUETOOTH", SourceInputState::Bt),
+            ("MEDIAPLAYER", SourceInputState::Mplay),
+            ("INTERNETRADIO", SourceInputState::Iradio),
+        ]
     }
 }
 
-#[allow(dead_code)] // currently MaxVolume is not used, but supported
-#[derive(Debug, PartialEq)]
-pub enum SetState {
-    Power(PowerState),
-    SourceInput(SourceInputState),
-    MaxVolume(u32),
-    MainVolume(u32),
+impl FromStr for SourceInputState {
+    type Err = crate::error::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        from_name(value)
+    }
 }
 
-impl SetState {
-    pub fn convert(&self) -> (State, StateValue) {
-        match *self {
-            SetState::MainVolume(i) => (State::MainVolume, StateValue::Integer(i)),
-            SetState::MaxVolume(i) => (State::MaxVolume, StateValue::Integer(i)),
-            SetState::Power(ps) => (State::Power, StateValue::Power(ps)),
-            SetState::SourceInput(si) => (State::SourceInput, StateValue::SourceInput(si)),
-        }
+impl Display for State {
+    fn fmt(&self, format: &mut Formatter) -> Result<(), Error> {
+        write!(format, "{}", command(*self).prefix)
     }
 }
 
@@ -146,6 +194,7 @@ impl Display for SetState {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum StateValue {
     Power(PowerState),
     SourceInput(SourceInputState),
@@ -179,9 +228,83 @@ pub fn get_state<T: ToString + Copy + std::fmt::Debug>(
     ))
 }
 
+/// Enums whose values can be given by name, e.g. on the command line.
+pub trait Named: Display + Copy + 'static {
+    const DESCRIPTION: &'static str;
+
+    fn variants() -> &'static [Self];
+
+    fn aliases() -> &'static [(&'static str, Self)] {
+        &[]
+    }
+}
+
+// ignores case and separators, so that "net usb" matches "NET/USB"
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_ascii_uppercase()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Finds the value matching the given name or one of its aliases.
+pub fn from_name<T: Named>(value: &str) -> Result<T, crate::error::Error> {
+    let normalized = normalize(value);
+    let candidates = T::variants()
+        .iter()
+        .map(|v| (normalize(&v.to_string()), *v))
+        .chain(T::aliases().iter().map(|(a, v)| (normalize(a), *v)));
+
+    let mut suggestion: Option<(usize, T)> = None;
+    for (name, v) in candidates {
+        let distance = edit_distance(&normalized, &name);
+        if distance == 0 {
+            return Ok(v);
+        }
+        if !matches!(suggestion, Some((d, _)) if distance >= d) {
+            suggestion = Some((distance, v));
+        }
+    }
+
+    let message = match suggestion {
+        Some((distance, v)) if distance <= (normalized.len() / 3).max(1) => {
+            format!(", did you mean {}?", v)
+        }
+        _ => format!(
+            ", use one of: {}",
+            T::variants()
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+    };
+    Err(crate::error::Error::Input(format!(
+        "given value {} does not match any {}{}",
+        value,
+        T::DESCRIPTION,
+        message
+    )))
+}
+
 #[cfg(test)]
 mod test {
-    use super::StateValue;
+    use super::{edit_distance, StateValue};
+    use crate::error::Error;
     use crate::state::{PowerState, SetState, SourceInputState, State};
     use std::collections::HashMap;
 
@@ -244,6 +367,78 @@ mod test {
     fn source_input_state_display() {
         assert_eq!("DVD", SourceInputState::Dvd.to_string());
         assert_eq!("FLICKR", SourceInputState::Flickr.to_string());
+        assert_eq!("SAT/CBL", SourceInputState::Satcbl.to_string());
+        assert_eq!("AUX7", SourceInputState::Aux7.to_string());
+        assert_eq!("8K", SourceInputState::EightK.to_string());
+        assert_eq!("TV AUDIO", SourceInputState::TvAudio.to_string());
+    }
+
+    #[test]
+    fn source_input_states_are_unique() {
+        let states = SourceInputState::states();
+        for state in states {
+            let same_name = states
+                .iter()
+                .filter(|s| s.to_string() == state.to_string())
+                .count();
+            assert_eq!(1, same_name, "{}", state);
+        }
+        assert!(!states.contains(&SourceInputState::Unknown));
+    }
+
+    #[test]
+    fn power_state_from_str() {
+        assert!(matches!("ON".parse::<PowerState>(), Ok(PowerState::On)));
+        assert!(matches!("on".parse::<PowerState>(), Ok(PowerState::On)));
+        assert!(matches!(
+            "Standby".parse::<PowerState>(),
+            Ok(PowerState::Standby)
+        ));
+        assert!(matches!(
+            "OFF".parse::<PowerState>(),
+            Err(Error::Input(e)) if e == "given value OFF does not match any power state, use one of: ON, STANDBY"
+        ));
+    }
+
+    #[test]
+    fn source_input_state_from_str() {
+        let parse = |value: &str| value.parse::<SourceInputState>().unwrap();
+        assert_eq!(SourceInputState::Dvd, parse("dvd"));
+        assert_eq!(SourceInputState::Netusb, parse("NET/USB"));
+        assert_eq!(SourceInputState::Netusb, parse("netusb"));
+        assert_eq!(SourceInputState::Bd, parse("bluray"));
+        assert_eq!(SourceInputState::Bd, parse("Blu-Ray"));
+        assert_eq!(SourceInputState::Satcbl, parse("sat"));
+        assert_eq!(SourceInputState::TvAudio, parse("tv audio"));
+        assert_eq!(SourceInputState::EightK, parse("8k"));
+    }
+
+    #[test]
+    fn source_input_state_from_str_suggests() {
+        assert!(matches!(
+            "DVX".parse::<SourceInputState>(),
+            Err(Error::Input(e)) if e == "given value DVX does not match any source input, did you mean DVD?"
+        ));
+        assert!(matches!(
+            "netusbb".parse::<SourceInputState>(),
+            Err(Error::Input(e)) if e.ends_with("did you mean NET/USB?")
+        ));
+        assert!(matches!(
+            "blutooth".parse::<SourceInputState>(),
+            Err(Error::Input(e)) if e.ends_with("did you mean BT?")
+        ));
+        assert!(matches!(
+            "SOUNDCLOUD".parse::<SourceInputState>(),
+            Err(Error::Input(e)) if e.starts_with("given value SOUNDCLOUD does not match any source input, use one of: CD, TUNER")
+        ));
+    }
+
+    #[test]
+    fn edit_distance_test() {
+        assert_eq!(0, edit_distance("DVD", "DVD"));
+        assert_eq!(1, edit_distance("DVX", "DVD"));
+        assert_eq!(3, edit_distance("", "DVD"));
+        assert_eq!(3, edit_distance("KITTEN", "SITTING"));
     }
 
     #[test]
@@ -270,6 +465,26 @@ mod test {
         assert_eq!("PW", ts(State::Power, StateValue::Unknown));
     }
 
+    #[test]
+    fn setstate_from_value() {
+        assert_eq!(
+            Some(SetState::MainVolume(230)),
+            SetState::from_value(State::MainVolume, StateValue::Integer(230))
+        );
+        assert_eq!(
+            Some(SetState::Power(PowerState::On)),
+            SetState::from_value(State::Power, StateValue::Power(PowerState::On))
+        );
+        assert_eq!(
+            None,
+            SetState::from_value(State::SourceInput, StateValue::Integer(230))
+        );
+        assert_eq!(
+            None,
+            SetState::from_value(State::Power, StateValue::Unknown)
+        );
+    }
+
     #[test]
     fn setstate_diplay() {
         assert_eq!("MV230", SetState::MainVolume(230).to_string());
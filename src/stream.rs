@@ -1,5 +1,9 @@
+use std::cmp::min;
 use std::io::{self, Read, Write};
-use std::net::TcpStream;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::fd::AsRawFd;
+use std::sync::Mutex;
+use std::time::Duration;
 
 #[cfg(test)]
 use mockall::{automock, mock, predicate::*};
@@ -22,7 +26,7 @@ impl ReadStream for TcpStream {
     }
 }
 
-pub trait ConnectionStream: Write {
+pub trait ConnectionStream: Write + Send {
     fn shutdownly(&self) -> io::Result<()>;
     fn get_readstream(&self) -> io::Result<Box<dyn ReadStream>>;
 }
@@ -50,21 +54,455 @@ mock! {
     }
 }
 
+// telnet commands, see RFC 854
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TelnetState {
+    Data,
+    Command,
+    Option(u8),
+    Subnegotiation,
+    SubnegotiationCommand,
+}
+
+/// Removes telnet commands from the received data and refuses every
+/// option the other side asks for. Sequences may be split across reads.
+struct TelnetFilter {
+    state: TelnetState,
+}
+
+impl Default for TelnetFilter {
+    fn default() -> Self {
+        TelnetFilter {
+            state: TelnetState::Data,
+        }
+    }
+}
+
+impl TelnetFilter {
+    fn filter(&mut self, input: &[u8], data: &mut Vec<u8>, replies: &mut Vec<u8>) {
+        for &byte in input {
+            self.state = match (self.state, byte) {
+                (TelnetState::Data, IAC) => TelnetState::Command,
+                (TelnetState::Data, _) => {
+                    data.push(byte);
+                    TelnetState::Data
+                }
+                // escaped 255 is data
+                (TelnetState::Command, IAC) => {
+                    data.push(IAC);
+                    TelnetState::Data
+                }
+                (TelnetState::Command, DO | DONT | WILL | WONT) => TelnetState::Option(byte),
+                (TelnetState::Command, SB) => TelnetState::Subnegotiation,
+                // NOP, GA and the like carry no data
+                (TelnetState::Command, _) => TelnetState::Data,
+                (TelnetState::Option(command), option) => {
+                    match command {
+                        DO => replies.extend_from_slice(&[IAC, WONT, option]),
+                        WILL => replies.extend_from_slice(&[IAC, DONT, option]),
+                        _ => {}
+                    }
+                    TelnetState::Data
+                }
+                (TelnetState::Subnegotiation, IAC) => TelnetState::SubnegotiationCommand,
+                (TelnetState::Subnegotiation, _) => TelnetState::Subnegotiation,
+                (TelnetState::SubnegotiationCommand, SE) => TelnetState::Data,
+                (TelnetState::SubnegotiationCommand, _) => TelnetState::Subnegotiation,
+            }
+        }
+    }
+}
+
+/// TCP connection, which hides telnet negotiation of bridges or terminal
+/// servers between us and the receiver.
+struct TelnetStream {
+    stream: TcpStream,
+}
+
+impl Write for TelnetStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl ConnectionStream for TelnetStream {
+    fn shutdownly(&self) -> io::Result<()> {
+        self.stream.shutdownly()
+    }
+
+    fn get_readstream(&self) -> io::Result<Box<dyn ReadStream>> {
+        Ok(Box::new(TelnetReadStream {
+            stream: self.stream.try_clone()?,
+            filtered: Mutex::default(),
+        }))
+    }
+}
+
+// received data without telnet commands, which is kept until it is consumed
+#[derive(Default)]
+struct Filtered {
+    filter: TelnetFilter,
+    data: Vec<u8>,
+    // peeking again without consuming waits for more data like a socket
+    peeked: usize,
+}
+
+struct TelnetReadStream {
+    stream: TcpStream,
+    filtered: Mutex<Filtered>,
+}
+
+impl TelnetReadStream {
+    /// Reads from the connection until more than `available` bytes are
+    /// buffered. Returns false if the connection was shut down.
+    fn fill(&self, filtered: &mut Filtered, available: usize) -> io::Result<bool> {
+        let mut chunk = [0; 4096];
+        while filtered.data.len() <= available {
+            let read_bytes = self.stream.peekly(&mut chunk)?;
+            if read_bytes == 0 {
+                return Ok(false);
+            }
+            self.stream.read_exactly(&mut chunk[..read_bytes])?;
+            let mut replies = Vec::new();
+            let Filtered { filter, data, .. } = filtered;
+            filter.filter(&chunk[..read_bytes], data, &mut replies);
+            if !replies.is_empty() {
+                (&self.stream).write_all(&replies)?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl ReadStream for TelnetReadStream {
+    fn peekly(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filtered = self.filtered.lock().unwrap();
+        let peeked = filtered.peeked;
+        if !self.fill(&mut filtered, peeked)? {
+            return Ok(0);
+        }
+        let length = min(buf.len(), filtered.data.len());
+        buf[..length].copy_from_slice(&filtered.data[..length]);
+        filtered.peeked = length;
+        Ok(length)
+    }
+
+    fn read_exactly(&self, buf: &mut [u8]) -> io::Result<()> {
+        let mut filtered = self.filtered.lock().unwrap();
+        let mut read_bytes = 0;
+        while read_bytes < buf.len() {
+            if !self.fill(&mut filtered, 0)? {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            let length = min(buf.len() - read_bytes, filtered.data.len());
+            buf[read_bytes..read_bytes + length].copy_from_slice(&filtered.data[..length]);
+            filtered.data.drain(..length);
+            filtered.peeked = 0;
+            read_bytes += length;
+        }
+        Ok(())
+    }
+}
+
+/// Socket options of network connections to the receiver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpOptions {
+    pub connect_timeout: Option<Duration>,
+    // the reader wakes up this often even if nothing was received
+    pub read_timeout: Option<Duration>,
+    // idle time before keepalive probes detect a receiver, which is switched off
+    pub keepalive: Option<Duration>,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        TcpOptions {
+            connect_timeout: Some(Duration::from_secs(5)),
+            read_timeout: None,
+            keepalive: None,
+        }
+    }
+}
+
+fn check(rc: libc::c_int) -> Result<(), io::Error> {
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn set_option(stream: &TcpStream, level: i32, name: i32, value: i32) -> Result<(), io::Error> {
+    // SAFETY: value lives during the call and its size is passed along
+    check(unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            &value as *const i32 as *const libc::c_void,
+            size_of::<i32>() as libc::socklen_t,
+        )
+    })
+}
+
+// unanswered probes are repeated this often once the connection was idle
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+fn set_keepalive(stream: &TcpStream, idle: Duration) -> Result<(), io::Error> {
+    set_option(stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    set_keepalive_times(stream, idle, min(idle, KEEPALIVE_INTERVAL))
+}
+
+fn seconds(duration: Duration) -> i32 {
+    duration.as_secs().clamp(1, i32::MAX as u64) as i32
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+fn set_keepalive_times(
+    stream: &TcpStream,
+    idle: Duration,
+    interval: Duration,
+) -> Result<(), io::Error> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let idle_option = libc::TCP_KEEPIDLE;
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    let idle_option = libc::TCP_KEEPALIVE;
+    set_option(stream, libc::IPPROTO_TCP, idle_option, seconds(idle))?;
+    set_option(
+        stream,
+        libc::IPPROTO_TCP,
+        libc::TCP_KEEPINTVL,
+        seconds(interval),
+    )
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+fn set_keepalive_times(
+    _stream: &TcpStream,
+    _idle: Duration,
+    _interval: Duration,
+) -> Result<(), io::Error> {
+    // the operating system keeps its own times
+    Ok(())
+}
+
+fn connect_timeout(
+    denon_name: &str,
+    denon_port: u16,
+    timeout: Duration,
+) -> Result<TcpStream, io::Error> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no address found");
+    for address in (denon_name, denon_port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Connects without telnet handling, which protocols like HTTP do not need.
+pub(crate) fn connect(
+    denon_name: &str,
+    denon_port: u16,
+    options: &TcpOptions,
+) -> Result<TcpStream, io::Error> {
+    let s = match options.connect_timeout {
+        Some(timeout) => connect_timeout(denon_name, denon_port, timeout)?,
+        None => TcpStream::connect((denon_name, denon_port))?,
+    };
+    s.set_read_timeout(options.read_timeout)?;
+    s.set_nonblocking(false)?;
+    if let Some(idle) = options.keepalive {
+        set_keepalive(&s, idle)?;
+    }
+    Ok(s)
+}
+
+#[cfg(test)]
 pub fn create_tcp_stream(
     denon_name: &str,
     denon_port: u16,
 ) -> Result<Box<dyn ConnectionStream>, io::Error> {
-    let s = TcpStream::connect((denon_name, denon_port))?;
-    s.set_read_timeout(None)?;
-    s.set_nonblocking(false)?;
-    Ok(Box::new(s))
+    create_tcp_stream_with(denon_name, denon_port, &TcpOptions::default())
+}
+
+pub fn create_tcp_stream_with(
+    denon_name: &str,
+    denon_port: u16,
+    options: &TcpOptions,
+) -> Result<Box<dyn ConnectionStream>, io::Error> {
+    let s = connect(denon_name, denon_port, options)?;
+    Ok(Box::new(TelnetStream { stream: s }))
+}
+
+/// Creates a new stream to the receiver, e.g. after the connection was lost.
+pub type StreamFactory = Box<dyn Fn() -> Result<Box<dyn ConnectionStream>, io::Error> + Send>;
+
+#[cfg(test)]
+pub fn tcp_stream_factory(denon_name: &str, denon_port: u16) -> StreamFactory {
+    tcp_stream_factory_with(denon_name, denon_port, TcpOptions::default())
+}
+
+pub fn tcp_stream_factory_with(
+    denon_name: &str,
+    denon_port: u16,
+    options: TcpOptions,
+) -> StreamFactory {
+    let denon_name = denon_name.to_string();
+    Box::new(move || create_tcp_stream_with(&denon_name, denon_port, &options))
 }
 
 #[cfg(test)]
 mod test {
-    use std::{io, net::TcpListener};
+    use std::io::{self, Read, Write};
+    use std::net::{TcpListener, TcpStream};
 
+    use super::{TelnetFilter, DO, DONT, IAC, SB, SE, WILL, WONT};
+    use crate::codec::LineCodec;
+    use crate::denon_connection::test::fill_lines;
     use crate::stream::create_tcp_stream;
+    #[cfg(target_os = "linux")]
+    use crate::stream::{connect, TcpOptions};
+    #[cfg(target_os = "linux")]
+    use std::os::fd::AsRawFd;
+    #[cfg(target_os = "linux")]
+    use std::time::Duration;
+
+    #[cfg(target_os = "linux")]
+    fn get_option(stream: &TcpStream, level: i32, name: i32) -> i32 {
+        let mut value: i32 = 0;
+        let mut size = size_of::<i32>() as libc::socklen_t;
+        // SAFETY: value and size live during the call
+        let rc = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                level,
+                name,
+                &mut value as *mut i32 as *mut libc::c_void,
+                &mut size,
+            )
+        };
+        assert_eq!(0, rc);
+        value
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn keepalive_probes_more_often_than_idle_time() -> Result<(), io::Error> {
+        let listener = TcpListener::bind("localhost:0")?;
+        let options = TcpOptions {
+            keepalive: Some(Duration::from_secs(60)),
+            ..TcpOptions::default()
+        };
+        let stream = connect("localhost", listener.local_addr()?.port(), &options)?;
+
+        assert_eq!(1, get_option(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE));
+        assert_eq!(
+            60,
+            get_option(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE)
+        );
+        assert_eq!(
+            5,
+            get_option(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL)
+        );
+        Ok(())
+    }
+
+    fn filter(filter: &mut TelnetFilter, input: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut data = Vec::new();
+        let mut replies = Vec::new();
+        filter.filter(input, &mut data, &mut replies);
+        (data, replies)
+    }
+
+    #[test]
+    fn filter_passes_plain_data() {
+        let mut telnet = TelnetFilter::default();
+        assert_eq!((b"PWON\r".to_vec(), vec![]), filter(&mut telnet, b"PWON\r"));
+    }
+
+    #[test]
+    fn filter_refuses_options() {
+        let mut telnet = TelnetFilter::default();
+        let input = [
+            IAC, DO, 1, b'P', b'W', IAC, WILL, 3, IAC, DONT, 5, IAC, WONT, 6,
+        ];
+        assert_eq!(
+            (b"PW".to_vec(), vec![IAC, WONT, 1, IAC, DONT, 3]),
+            filter(&mut telnet, &input)
+        );
+    }
+
+    #[test]
+    fn filter_skips_subnegotiation_and_commands() {
+        let mut telnet = TelnetFilter::default();
+        let input = [IAC, SB, 24, 1, IAC, SE, b'M', IAC, 241, b'V', IAC, IAC];
+        assert_eq!((vec![b'M', b'V', IAC], vec![]), filter(&mut telnet, &input));
+    }
+
+    #[test]
+    fn filter_handles_split_sequences() {
+        let mut telnet = TelnetFilter::default();
+        assert_eq!(
+            (b"SI".to_vec(), vec![]),
+            filter(&mut telnet, &[b'S', b'I', IAC])
+        );
+        assert_eq!((vec![], vec![]), filter(&mut telnet, &[DO]));
+        assert_eq!(
+            (b"CD\r".to_vec(), vec![IAC, WONT, 24]),
+            filter(&mut telnet, &[24, b'C', b'D', b'\r'])
+        );
+    }
+
+    #[test]
+    fn tcp_stream_strips_and_answers_negotiation() -> Result<(), io::Error> {
+        let listener = TcpListener::bind("localhost:0")?;
+        let addr = listener.local_addr()?;
+        let stream = create_tcp_stream(addr.ip().to_string().as_str(), addr.port())?;
+        let (mut to_client, _) = listener.accept()?;
+        let read_stream = stream.get_readstream()?;
+
+        to_client.write_all(&[IAC, DO, 1, IAC, WILL, 3])?;
+        to_client.write_all(b"PWON\rMV")?;
+        let mut codec = LineCodec::default();
+        let mut lines = fill_lines(&mut codec, read_stream.as_ref(), 1)?;
+        // the incomplete line is read once the rest arrives
+        let writer = std::thread::spawn(move || -> Result<TcpStream, io::Error> {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            to_client.write_all(b"50\r")?;
+            Ok(to_client)
+        });
+        lines.append(&mut fill_lines(&mut codec, read_stream.as_ref(), 1)?);
+        assert_eq!(vec!["PWON", "MV50"], lines);
+        let mut to_client = writer.join().unwrap()?;
+
+        let mut replies = [0; 6];
+        to_client.read_exact(&mut replies)?;
+        assert_eq!([IAC, WONT, 1, IAC, DONT, 3], replies);
+        Ok(())
+    }
 
     #[test]
     fn connects_to_server() -> Result<(), io::Error> {
@@ -77,6 +515,6 @@ mod test {
     #[test]
     fn fails_to_connect_and_returns_unknown() {
         let dc = create_tcp_stream("value", 0);
-        assert!(matches!(dc, Err(_)));
+        assert!(dc.is_err());
     }
 }
@@ -1,5 +1,4 @@
 use assert_cmd::prelude::*; // Add methods on commands
-use denon_control::read;
 use parameterized::parameterized;
 use predicates::prelude::*; // Used for writing assertions
 use predicates::str::contains;
@@ -10,6 +9,28 @@ use std::{
     thread::{self, JoinHandle},
 }; // Run programs
 
+/// Reads up to `lines` lines ending with `\r`, fewer if the stream ends first.
+fn read(mut stream: &TcpStream, lines: u8) -> Result<Vec<String>, io::Error> {
+    let mut result = Vec::<String>::new();
+    let mut line = Vec::<u8>::new();
+    let mut byte = [0; 1];
+    while (lines as usize) != result.len() {
+        match stream.read(&mut byte) {
+            Ok(0) if result.is_empty() => {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+            }
+            Err(e) if result.is_empty() => return Err(e),
+            Ok(0) | Err(_) => break,
+            Ok(_) if byte[0] == b'\r' => {
+                result.push(String::from_utf8_lossy(&line).trim().to_string());
+                line.clear();
+            }
+            Ok(_) => line.push(byte[0]),
+        }
+    }
+    Ok(result)
+}
+
 fn create_acceptor_thread() -> Result<(JoinHandle<Result<TcpStream, io::Error>>, u16), io::Error> {
     let listen_socket = TcpListener::bind("localhost:0")?;
     let local_port = listen_socket.local_addr()?.port();
@@ -33,6 +54,32 @@ fn prints_help() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+#[test]
+fn help_lists_options_of_commands() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("denon-control")?;
+    cmd.arg("--help");
+    cmd.assert()
+        .success()
+        .stdout(contains("--power POWER_MODE"))
+        .stdout(contains("set power: ON, STANDBY"))
+        .stdout(contains("set volume: 0..50"));
+
+    Ok(())
+}
+
+#[test]
+fn prints_commands() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("denon-control")?;
+    cmd.arg("commands");
+    cmd.assert()
+        .success()
+        .stdout(contains("PW    Power"))
+        .stdout(contains("MVMAX MaxVolume"))
+        .stdout(contains("using receiver").not());
+
+    Ok(())
+}
+
 #[test]
 fn fails_to_connect_and_prints_error() -> Result<(), Box<dyn std::error::Error>> {
     let mut cmd = Command::cargo_bin("denon-control")?;
@@ -69,7 +116,7 @@ fn losing_connection_will_print_error() -> Result<(), Box<dyn std::error::Error>
     let acceptor = thread::spawn(move || -> Result<(), io::Error> {
         let mut to_receiver = listen_socket.accept()?.0;
         let mut buf = [0; 100];
-        to_receiver.read(&mut buf)?;
+        let _ = to_receiver.read(&mut buf)?;
         Ok(())
     });
 
@@ -78,7 +125,7 @@ fn losing_connection_will_print_error() -> Result<(), Box<dyn std::error::Error>
         .arg("--status");
     cmd.assert().failure().stderr(contains("Error: IO"));
 
-    let _ = acceptor.join().unwrap()?;
+    acceptor.join().unwrap()?;
 
     Ok(())
 }
@@ -100,13 +147,13 @@ fn queries_receiver_state_and_gets_state_one_by_one(
 
     let acceptor = thread::spawn(move || -> Result<(TcpStream, Vec<String>), io::Error> {
         let mut to_receiver = listen_socket.accept()?.0;
-        let mut received_data = read(&mut to_receiver, 1)?;
+        let mut received_data = read(&to_receiver, 1)?;
         to_receiver.write_all(format!("PW{}\r", power).as_bytes())?;
-        received_data.append(&mut read(&mut to_receiver, 1)?);
+        received_data.append(&mut read(&to_receiver, 1)?);
         to_receiver.write_all(format!("SI{}\r", input).as_bytes())?;
-        received_data.append(&mut read(&mut to_receiver, 1)?);
+        received_data.append(&mut read(&to_receiver, 1)?);
         to_receiver.write_all(format!("MV{}\r", volume).as_bytes())?;
-        received_data.append(&mut read(&mut to_receiver, 1)?);
+        received_data.append(&mut read(&to_receiver, 1)?);
         to_receiver.write_all(format!("MVMAX{}\r", max_volume).as_bytes())?;
         Ok((to_receiver, received_data))
     });
@@ -145,7 +192,7 @@ fn queries_receiver_state_and_gets_all_states_at_once(
 
     let acceptor = thread::spawn(move || -> Result<(TcpStream, Vec<String>), io::Error> {
         let mut to_receiver = listen_socket.accept()?.0;
-        let received_data = read(&mut to_receiver, 1)?;
+        let received_data = read(&to_receiver, 1)?;
         let response = format!(
             "PW{}\rSI{}\rMV{}\rMVMAX{}\r",
             power, input, volume, max_volume
@@ -184,8 +231,8 @@ fn sets_receiver_state() -> Result<(), Box<dyn std::error::Error>> {
         .arg("127");
     cmd.assert().success();
 
-    let mut to_receiver = acceptor.join().unwrap()?;
-    let received_data = read(&mut to_receiver, 10)?;
+    let to_receiver = acceptor.join().unwrap()?;
+    let received_data = read(&to_receiver, 10)?;
 
     assert!(received_data.contains(&String::from("SICD")));
     assert!(received_data.contains(&String::from("MV50")));
@@ -205,14 +252,105 @@ fn sets_power(power: &str) -> Result<(), Box<dyn std::error::Error>> {
         .arg(power);
     cmd.assert().success();
 
-    let mut to_receiver = acceptor.join().unwrap()?;
-    let received_data = read(&mut to_receiver, 10)?;
+    let to_receiver = acceptor.join().unwrap()?;
+    let received_data = read(&to_receiver, 10)?;
 
     assert!(received_data.contains(&format!("PW{}", power)));
 
     Ok(())
 }
 
+#[test]
+fn sets_power_with_reconnect() -> Result<(), Box<dyn std::error::Error>> {
+    let (acceptor, local_port) = create_acceptor_thread()?;
+    let mut cmd = Command::cargo_bin("denon-control")?;
+
+    cmd.arg("--address")
+        .arg(format!("localhost:{}", local_port))
+        .arg("--reconnect")
+        .arg("--power")
+        .arg("ON");
+    cmd.assert().success();
+
+    let to_receiver = acceptor.join().unwrap()?;
+    let received_data = read(&to_receiver, 10)?;
+
+    assert!(received_data.contains(&String::from("PWON")));
+
+    Ok(())
+}
+
+#[test]
+fn verify_prints_confirmed_power() -> Result<(), Box<dyn std::error::Error>> {
+    let listen_socket = TcpListener::bind("localhost:0")?;
+    let local_port = listen_socket.local_addr()?.port();
+    let responder = thread::spawn(move || -> Result<Vec<String>, io::Error> {
+        let mut to_receiver = listen_socket.accept()?.0;
+        let received = read(&to_receiver, 1)?;
+        to_receiver.write_all(b"PWON\r")?;
+        Ok(received)
+    });
+
+    let mut cmd = Command::cargo_bin("denon-control")?;
+    cmd.arg("--address")
+        .arg(format!("localhost:{}", local_port))
+        .arg("--verify")
+        .arg("--power")
+        .arg("ON");
+    cmd.assert()
+        .success()
+        .stdout(contains("Power(ON) confirmed"));
+
+    assert_eq!(vec!["PWON"], responder.join().unwrap()?);
+
+    Ok(())
+}
+
+#[test]
+fn verify_accepts_volume_echoed_in_half_steps() -> Result<(), Box<dyn std::error::Error>> {
+    let listen_socket = TcpListener::bind("localhost:0")?;
+    let local_port = listen_socket.local_addr()?.port();
+    let responder = thread::spawn(move || -> Result<Vec<String>, io::Error> {
+        let mut to_receiver = listen_socket.accept()?.0;
+        let received = read(&to_receiver, 1)?;
+        to_receiver.write_all(b"MV45\r")?;
+        Ok(received)
+    });
+
+    let mut cmd = Command::cargo_bin("denon-control")?;
+    cmd.arg("--address")
+        .arg(format!("localhost:{}", local_port))
+        .arg("--verify")
+        .arg("--volume")
+        .arg("45");
+    cmd.assert()
+        .success()
+        .stdout(contains("MainVolume(450) confirmed"));
+
+    assert_eq!(vec!["MV45"], responder.join().unwrap()?);
+
+    Ok(())
+}
+
+#[test]
+fn verify_without_echo_prints_error() -> Result<(), Box<dyn std::error::Error>> {
+    let (acceptor, local_port) = create_acceptor_thread()?;
+    let mut cmd = Command::cargo_bin("denon-control")?;
+
+    cmd.arg("--address")
+        .arg(format!("localhost:{}", local_port))
+        .arg("--verify")
+        .arg("--power")
+        .arg("STANDBY");
+    cmd.assert()
+        .failure()
+        .stderr(contains("Error: Unconfirmed(Power(Standby))"));
+
+    acceptor.join().unwrap()?;
+
+    Ok(())
+}
+
 #[parameterized(power = {"OFF", "BLUB"})]
 fn setting_invalid_power_prints_error(power: &str) -> Result<(), Box<dyn std::error::Error>> {
     let (acceptor, local_port) = create_acceptor_thread()?;
@@ -226,13 +364,13 @@ fn setting_invalid_power_prints_error(power: &str) -> Result<(), Box<dyn std::er
         .failure()
         .stderr(contains(format!("given value {} does not match", power)));
 
-    let mut to_receiver = acceptor.join().unwrap()?;
-    assert!(read(&mut to_receiver, 10).is_err());
+    let to_receiver = acceptor.join().unwrap()?;
+    assert!(read(&to_receiver, 10).is_err());
 
     Ok(())
 }
 
-#[parameterized(source_input = {"CD", "DVD", "BD", "NET/USB"})]
+#[parameterized(source_input = {"CD", "DVD", "BD", "NET/USB", "SAT/CBL"})]
 fn sets_source_input(source_input: &str) -> Result<(), Box<dyn std::error::Error>> {
     let (acceptor, local_port) = create_acceptor_thread()?;
     let mut cmd = Command::cargo_bin("denon-control")?;
@@ -243,15 +381,55 @@ fn sets_source_input(source_input: &str) -> Result<(), Box<dyn std::error::Error
         .arg(source_input);
     cmd.assert().success();
 
-    let mut to_receiver = acceptor.join().unwrap()?;
-    let received_data = read(&mut to_receiver, 10)?;
+    let to_receiver = acceptor.join().unwrap()?;
+    let received_data = read(&to_receiver, 10)?;
 
     assert!(received_data.contains(&format!("SI{}", source_input)));
 
     Ok(())
 }
 
-#[parameterized(input = {"SPOTIFY", "BLUB"})]
+#[parameterized(input = {"bluray", "netusb", "sat", "cd"},
+                expected = {"SIBD", "SINET/USB", "SISAT/CBL", "SICD"})]
+fn sets_source_input_by_alias(
+    input: &str,
+    expected: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (acceptor, local_port) = create_acceptor_thread()?;
+    let mut cmd = Command::cargo_bin("denon-control")?;
+
+    cmd.arg("--address")
+        .arg(format!("localhost:{}", local_port))
+        .arg("--input")
+        .arg(input);
+    cmd.assert().success();
+
+    let to_receiver = acceptor.join().unwrap()?;
+    let received_data = read(&to_receiver, 10)?;
+
+    assert!(received_data.contains(&expected.to_string()));
+
+    Ok(())
+}
+
+#[test]
+fn misspelled_input_prints_suggestion() -> Result<(), Box<dyn std::error::Error>> {
+    let (acceptor, local_port) = create_acceptor_thread()?;
+    let mut cmd = Command::cargo_bin("denon-control")?;
+
+    cmd.arg("--address")
+        .arg(format!("localhost:{}", local_port))
+        .arg("--input")
+        .arg("DVX");
+    cmd.assert().failure().stderr(contains("did you mean DVD?"));
+
+    let to_receiver = acceptor.join().unwrap()?;
+    assert!(read(&to_receiver, 10).is_err());
+
+    Ok(())
+}
+
+#[parameterized(input = {"SOUNDCLOUD", "BLUB"})]
 fn input_prints_error(input: &str) -> Result<(), Box<dyn std::error::Error>> {
     let (acceptor, local_port) = create_acceptor_thread()?;
     let mut cmd = Command::cargo_bin("denon-control")?;
@@ -264,8 +442,55 @@ fn input_prints_error(input: &str) -> Result<(), Box<dyn std::error::Error>> {
         .failure()
         .stderr(contains(format!("given value {} does not match", input)));
 
-    let mut to_receiver = acceptor.join().unwrap()?;
-    assert!(read(&mut to_receiver, 10).is_err());
+    let to_receiver = acceptor.join().unwrap()?;
+    assert!(read(&to_receiver, 10).is_err());
+
+    Ok(())
+}
+
+#[parameterized(model = {"AVR-X", "AVR-X", "AVR-1912", "AVR-1912"},
+                input = {"NAPSTER", "FLICKR", "HEOS", "SPOTIFY"})]
+fn input_not_supported_by_model_prints_error(
+    model: &str,
+    input: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (acceptor, local_port) = create_acceptor_thread()?;
+    let mut cmd = Command::cargo_bin("denon-control")?;
+
+    cmd.arg("--address")
+        .arg(format!("localhost:{}", local_port))
+        .arg("--model")
+        .arg(model)
+        .arg("--input")
+        .arg(input);
+    cmd.assert().failure().stderr(contains(format!(
+        "source input {} is not supported by {}",
+        input, model
+    )));
+
+    let to_receiver = acceptor.join().unwrap()?;
+    assert!(read(&to_receiver, 10).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn sets_source_input_with_encoding_of_model() -> Result<(), Box<dyn std::error::Error>> {
+    let (acceptor, local_port) = create_acceptor_thread()?;
+    let mut cmd = Command::cargo_bin("denon-control")?;
+
+    cmd.arg("--address")
+        .arg(format!("localhost:{}", local_port))
+        .arg("--model")
+        .arg("AVR-X2700H")
+        .arg("--input")
+        .arg("TV AUDIO");
+    cmd.assert().success();
+
+    let to_receiver = acceptor.join().unwrap()?;
+    let received_data = read(&to_receiver, 10)?;
+
+    assert!(received_data.contains(&String::from("SITV")));
 
     Ok(())
 }
@@ -281,10 +506,10 @@ fn sets_volume(volume: u16) -> Result<(), Box<dyn std::error::Error>> {
         .arg(volume.to_string());
     cmd.assert().success();
 
-    let mut to_receiver = acceptor.join().unwrap()?;
-    let received_data = read(&mut to_receiver, 10)?;
+    let to_receiver = acceptor.join().unwrap()?;
+    let received_data = read(&to_receiver, 10)?;
 
-    assert!(received_data.contains(&format!("MV{}", volume)));
+    assert!(received_data.contains(&format!("MV{:02}", volume)));
 
     Ok(())
 }
@@ -302,8 +527,8 @@ fn setting_invalid_volume_prints_error(volume: &str) -> Result<(), Box<dyn std::
         .failure()
         .stderr(contains(String::from("ParseInt")));
 
-    let mut to_receiver = acceptor.join().unwrap()?;
-    assert!(read(&mut to_receiver, 10).is_err());
+    let to_receiver = acceptor.join().unwrap()?;
+    assert!(read(&to_receiver, 10).is_err());
 
     Ok(())
 }
@@ -319,10 +544,209 @@ fn caps_higher_volumes_to_50(volume: u16) -> Result<(), Box<dyn std::error::Erro
         .arg(volume.to_string());
     cmd.assert().success();
 
-    let mut to_receiver = acceptor.join().unwrap()?;
-    let received_data = read(&mut to_receiver, 10)?;
+    let to_receiver = acceptor.join().unwrap()?;
+    let received_data = read(&to_receiver, 10)?;
 
     assert!(received_data.contains(&String::from("MV50")));
 
     Ok(())
 }
+
+#[test]
+fn raw_command_prints_replies() -> Result<(), Box<dyn std::error::Error>> {
+    let listen_socket = TcpListener::bind("localhost:0")?;
+    let local_port = listen_socket.local_addr()?.port();
+    let mut cmd = Command::cargo_bin("denon-control")?;
+
+    let acceptor = thread::spawn(move || -> Result<(TcpStream, Vec<String>), io::Error> {
+        let mut to_receiver = listen_socket.accept()?.0;
+        let received_data = read(&to_receiver, 1)?;
+        to_receiver.write_all("PSBAS 50\rPSTRE 45\r".as_bytes())?;
+        Ok((to_receiver, received_data))
+    });
+
+    cmd.arg("--address")
+        .arg(format!("localhost:{}", local_port))
+        .arg("--query")
+        .arg("PS")
+        .arg("--wait")
+        .arg("300");
+    cmd.assert()
+        .success()
+        .stdout(contains("PSBAS 50\nPSTRE 45\n"));
+
+    let (_, received_data) = acceptor.join().unwrap()?;
+    assert_eq!(vec![String::from("PS?")], received_data);
+
+    Ok(())
+}
+
+#[test]
+fn query_prints_decoded_display_lines() -> Result<(), Box<dyn std::error::Error>> {
+    let listen_socket = TcpListener::bind("localhost:0")?;
+    let local_port = listen_socket.local_addr()?.port();
+    let mut cmd = Command::cargo_bin("denon-control")?;
+
+    let acceptor = thread::spawn(move || -> Result<(TcpStream, Vec<String>), io::Error> {
+        let mut to_receiver = listen_socket.accept()?.0;
+        let received_data = read(&to_receiver, 1)?;
+        to_receiver.write_all(b"NSE0Now Playing\rNSE1\x03Mot\xf6rhead\r")?;
+        Ok((to_receiver, received_data))
+    });
+
+    cmd.arg("--address")
+        .arg(format!("localhost:{}", local_port))
+        .arg("--query")
+        .arg("NSE")
+        .arg("--wait")
+        .arg("300");
+    cmd.assert().success().stdout(contains(
+        "NSE0 Now Playing\nNSE1 [cursor, playable] Motörhead\n",
+    ));
+
+    let (_, received_data) = acceptor.join().unwrap()?;
+    assert_eq!(vec![String::from("NSE?")], received_data);
+
+    Ok(())
+}
+
+#[test]
+fn sets_power_via_http() -> Result<(), Box<dyn std::error::Error>> {
+    let listen_socket = TcpListener::bind("localhost:0")?;
+    let local_port = listen_socket.local_addr()?.port();
+    let server = thread::spawn(move || -> Result<String, io::Error> {
+        let mut to_receiver = listen_socket.accept()?.0;
+        let mut request = String::new();
+        let mut buffer = [0; 1024];
+        while !request.ends_with("\r\n\r\n") {
+            let length = to_receiver.read(&mut buffer)?;
+            if length == 0 {
+                break;
+            }
+            request += &String::from_utf8_lossy(&buffer[..length]);
+        }
+        to_receiver.write_all(b"HTTP/1.1 200 OK\r\n\r\n")?;
+        Ok(request)
+    });
+
+    let mut cmd = Command::cargo_bin("denon-control")?;
+    cmd.arg("--address")
+        .arg(format!("localhost:{}", local_port))
+        .arg("--transport")
+        .arg("http")
+        .arg("--power")
+        .arg("ON");
+    cmd.assert().success();
+
+    let request = server.join().unwrap()?;
+    assert!(request.starts_with("GET /goform/formiPhoneAppDirect.xml?PWON HTTP/1.0\r\n"));
+
+    Ok(())
+}
+
+#[test]
+fn unknown_transport_prints_error() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("denon-control")?;
+    cmd.arg("--address")
+        .arg("localhost")
+        .arg("--transport")
+        .arg("ftp");
+    cmd.assert()
+        .failure()
+        .stderr(contains("given transport ftp is not known"));
+
+    Ok(())
+}
+
+#[test]
+fn heos_prints_play_state() -> Result<(), Box<dyn std::error::Error>> {
+    let listen_socket = TcpListener::bind("localhost:0")?;
+    let local_port = listen_socket.local_addr()?.port();
+    let server = thread::spawn(move || -> Result<Vec<String>, io::Error> {
+        let mut to_receiver = listen_socket.accept()?.0;
+        let mut reader = io::BufReader::new(to_receiver.try_clone()?);
+        let mut commands = Vec::new();
+        for reply in [
+            "{\"heos\": {\"command\": \"player/get_players\", \"result\": \"success\", \"message\": \"\"}, \"payload\": [{\"name\": \"Living Room\", \"pid\": 1717, \"model\": \"Denon AVR-X2700H\"}]}\r\n",
+            "{\"heos\": {\"command\": \"player/get_play_state\", \"result\": \"success\", \"message\": \"pid=1717&state=play\"}}\r\n",
+        ] {
+            let mut line = String::new();
+            io::BufRead::read_line(&mut reader, &mut line)?;
+            commands.push(line.trim().to_string());
+            to_receiver.write_all(reply.as_bytes())?;
+        }
+        Ok(commands)
+    });
+
+    let mut cmd = Command::cargo_bin("denon-control")?;
+    cmd.arg("--address")
+        .arg(format!("localhost:{}", local_port))
+        .arg("heos")
+        .arg("state");
+    cmd.assert().success().stdout(contains("play\n"));
+
+    assert_eq!(
+        vec![
+            "heos://player/get_players",
+            "heos://player/get_play_state?pid=1717"
+        ],
+        server.join().unwrap()?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn heos_plays_url_on_player() -> Result<(), Box<dyn std::error::Error>> {
+    let listen_socket = TcpListener::bind("localhost:0")?;
+    let local_port = listen_socket.local_addr()?.port();
+    let server = thread::spawn(move || -> Result<Vec<String>, io::Error> {
+        let mut to_receiver = listen_socket.accept()?.0;
+        let mut reader = io::BufReader::new(to_receiver.try_clone()?);
+        let mut commands = Vec::new();
+        for reply in [
+            "{\"heos\": {\"command\": \"player/get_players\", \"result\": \"success\", \"message\": \"\"}, \"payload\": [{\"name\": \"Living Room\", \"pid\": 1717}, {\"name\": \"Kitchen\", \"pid\": -42}]}\r\n",
+            "{\"heos\": {\"command\": \"browse/play_stream\", \"result\": \"success\", \"message\": \"pid=-42&url=http://radio.example/stream\"}}\r\n",
+        ] {
+            let mut line = String::new();
+            io::BufRead::read_line(&mut reader, &mut line)?;
+            commands.push(line.trim().to_string());
+            to_receiver.write_all(reply.as_bytes())?;
+        }
+        Ok(commands)
+    });
+
+    let mut cmd = Command::cargo_bin("denon-control")?;
+    cmd.arg("--address")
+        .arg(format!("localhost:{}", local_port))
+        .arg("--player")
+        .arg("Kitchen")
+        .arg("heos")
+        .arg("play-url")
+        .arg("http://radio.example/stream");
+    cmd.assert().success().stdout(contains(
+        "{\"pid\":-42,\"url\":\"http://radio.example/stream\"}",
+    ));
+
+    assert_eq!(
+        vec![
+            "heos://player/get_players",
+            "heos://browse/play_stream?pid=-42&url=http://radio.example/stream"
+        ],
+        server.join().unwrap()?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn missing_serial_device_prints_error() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("denon-control")?;
+    cmd.arg("--serial")
+        .arg("/dev/does-not-exist")
+        .arg("--power")
+        .arg("ON");
+    cmd.assert().failure().stderr(contains("NotFound"));
+
+    Ok(())
+}